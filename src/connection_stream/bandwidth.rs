@@ -0,0 +1,232 @@
+use wasm_bindgen::prelude::*;
+use web_sys::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::spawn_local;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use crate::{js_await, get, set};
+use crate::connection_stream::simulcast::{LayerState, Simulcast, SimulcastLayer};
+use crate::error::Error;
+
+// Upload budget shared by all the peers of the mesh in automatic mode
+const AUTO_UPLINK_KBPS: u32 = 3000;
+const AUTO_MIN_KBPS: u32 = 150;
+const AUTO_MAX_KBPS: u32 = 1500;
+
+// Caps on what is sent to a peer, None leaves the browser's default
+#[derive(Clone, Copy, Default)]
+pub struct SendLimits {
+    pub max_bitrate_kbps: Option<u32>,
+    pub max_framerate: Option<f64>,
+    pub scale_resolution_down_by: Option<f64>,
+}
+
+impl SendLimits {
+    // The lower of the two bitrate caps wins
+    pub fn with_bitrate_cap(self, cap: Option<u32>) -> SendLimits {
+        let max_bitrate_kbps = match (self.max_bitrate_kbps, cap) {
+            (Some(limit), Some(cap)) => Some(limit.min(cap)),
+            (limit, cap) => limit.or(cap)
+        };
+        SendLimits { max_bitrate_kbps, ..self }
+    }
+
+    // The scales multiply, the layer's bitrate is one more cap
+    fn for_layer(self, layer: &SimulcastLayer) -> SendLimits {
+        let scale = self.scale_resolution_down_by.unwrap_or(1.) * layer.scale_resolution_down_by;
+        SendLimits { scale_resolution_down_by: Some(scale), ..self.with_bitrate_cap(layer.max_bitrate_kbps) }
+    }
+}
+
+// No cap with a single peer, then the budget is split between the peers
+pub fn auto_bitrate_kbps(peers: usize) -> Option<u32> {
+    match peers {
+        0 | 1 => None,
+        peers => Some((AUTO_UPLINK_KBPS / peers as u32).clamp(AUTO_MIN_KBPS, AUTO_MAX_KBPS))
+    }
+}
+
+fn set_or_delete(encoding: &js_sys::Object, key: &str, value: Option<f64>) {
+    match value {
+        Some(value) => {
+            set![encoding => key, value];
+        }
+        None => {
+            let _ = js_sys::Reflect::delete_property(encoding, &JsValue::from_str(key));
+        }
+    }
+}
+
+pub async fn apply_limits(sender: RtcRtpSender, limits: SendLimits, layers: Vec<LayerState>) -> Result<(), Error> {
+    let parameters = sender.get_parameters();
+    // Some browsers list no encodings before negotiation, Senders::apply runs again once it's done
+    let encodings = js_sys::Array::from(&get![parameters => "encodings"]);
+    if encodings.length() == 0 {
+        return Ok(());
+    }
+    // A peer that can't receive simulcast, or an answer, leaves a single encoding,
+    // it takes the settings of the largest layer asked for
+    let single = encodings.length() == 1;
+    for encoding in encodings.iter() {
+        let rid = get![encoding => "rid"].as_string();
+        let state = layers.iter().find(|state| Some(&state.layer.rid) == rid.as_ref());
+        let (layer, active) = match (state, single) {
+            (_, true) => (layers.iter().rev().find(|state| state.active).or(layers.last()).map(|state| &state.layer), true),
+            (Some(state), false) => (Some(&state.layer), state.active),
+            (None, false) => (None, true)
+        };
+        let limits = match layer {
+            Some(layer) => limits.for_layer(layer),
+            None => limits
+        };
+        let encoding: js_sys::Object = encoding.unchecked_into();
+        set_or_delete(&encoding, "maxBitrate", limits.max_bitrate_kbps.map(|kbps| kbps as f64 * 1000.));
+        set_or_delete(&encoding, "maxFramerate", limits.max_framerate);
+        set_or_delete(&encoding, "scaleResolutionDownBy", limits.scale_resolution_down_by);
+        set![encoding => "active", active];
+    }
+    js_await![sender.set_parameters_with_parameters(&parameters)]?;
+    Ok(())
+}
+
+fn is_video(sender: &RtcRtpSender) -> bool {
+    sender.track().map(|track| track.kind() == "video").unwrap_or(false)
+}
+
+// The local tracks sent to a peer, with the caps and simulcast layers of their video
+pub struct Senders {
+    // Local track id => sender
+    senders: RefCell<HashMap<String, RtcRtpSender>>,
+    limits: Cell<SendLimits>,
+    // Set by the automatic mode of Streaming, on top of `limits`
+    auto_bitrate: Cell<Option<u32>>,
+    simulcast: Simulcast,
+    // Local track id => rids asked for by the peer
+    requested: RefCell<HashMap<String, Vec<String>>>,
+    // Track id of the camera, the only video sized after the peer's tile
+    camera: RefCell<Option<String>>,
+}
+
+impl Senders {
+    pub fn new(simulcast: Simulcast) -> Senders {
+        Senders {
+            senders: RefCell::new(HashMap::new()),
+            limits: Cell::new(SendLimits::default()),
+            auto_bitrate: Cell::new(None),
+            simulcast,
+            requested: RefCell::new(HashMap::new()),
+            camera: RefCell::new(None),
+        }
+    }
+
+    // Simulcast needs a transceiver of its own, which only an offer can add. The
+    // answering side passes false to send on the transceivers of the remote offer.
+    pub fn add(&self, peer: &RtcPeerConnection, track: &MediaStreamTrack, stream: &MediaStream, simulcast: bool) -> bool {
+        let mut senders = self.senders.borrow_mut();
+        if senders.contains_key(&track.id()) {
+            return false;
+        }
+        let video = track.kind() == "video";
+        let sender = match video && simulcast && self.simulcast.is_enabled() {
+            true => peer.add_transceiver_with_media_stream_track_and_init(track, &self.simulcast.transceiver_init(stream)).sender(),
+            false => peer.add_track_0(track, stream)
+        };
+        if video {
+            self.apply_sender(&track.id(), sender.clone());
+        }
+        senders.insert(track.id(), sender);
+        true
+    }
+
+    pub fn set_camera(&self, track_id: &str) {
+        *self.camera.borrow_mut() = Some(track_id.to_string());
+    }
+
+    pub fn remove(&self, track_id: &str) -> Option<RtcRtpSender> {
+        self.requested.borrow_mut().remove(track_id);
+        self.senders.borrow_mut().remove(track_id)
+    }
+
+    // Swaps the media of a sender in place, no renegotiation is needed. The
+    // layers asked for and the camera role stay with the sender.
+    pub fn replace(&self, old_track_id: &str, track: &MediaStreamTrack) -> Option<js_sys::Promise> {
+        let mut senders = self.senders.borrow_mut();
+        let sender = senders.remove(old_track_id)?;
+        let promise = sender.replace_track(Some(track));
+        senders.insert(track.id(), sender);
+        let mut requested = self.requested.borrow_mut();
+        if let Some(rids) = requested.remove(old_track_id) {
+            requested.insert(track.id(), rids);
+        }
+        let mut camera = self.camera.borrow_mut();
+        if camera.as_deref() == Some(old_track_id) {
+            *camera = Some(track.id());
+        }
+        Some(promise)
+    }
+
+    pub fn limits(&self) -> SendLimits {
+        self.limits.get()
+    }
+
+    pub fn set_limits(&self, limits: SendLimits) {
+        self.limits.set(limits);
+        self.apply();
+    }
+
+    pub fn set_auto_bitrate(&self, kbps: Option<u32>) {
+        if self.auto_bitrate.replace(kbps) != kbps {
+            self.apply();
+        }
+    }
+
+    // Applies to every video sent to the peer
+    pub fn request_layers(&self, rids: Vec<String>) {
+        if !self.simulcast.is_enabled() {
+            return;
+        }
+        for (track_id, sender) in self.senders.borrow().iter().filter(|(_, sender)| is_video(sender)) {
+            self.requested.borrow_mut().insert(track_id.clone(), rids.clone());
+            self.apply_sender(track_id, sender.clone());
+        }
+    }
+
+    // Only the camera is sized after the peer's tile, a screen share keeps its layers
+    pub fn fit_tile(&self, width: f64, height: f64) {
+        if !self.simulcast.is_enabled() {
+            return;
+        }
+        let camera = match self.camera.borrow().clone() {
+            Some(camera) => camera,
+            None => return
+        };
+        let sender = match self.senders.borrow().get(&camera) {
+            Some(sender) => sender.clone(),
+            None => return
+        };
+        let rid = sender.track().and_then(|track| self.simulcast.layer_for_tile(&track, width, height));
+        if let Some(rid) = rid {
+            self.requested.borrow_mut().insert(camera.clone(), vec![rid]);
+            self.apply_sender(&camera, sender);
+        }
+    }
+
+    fn apply_sender(&self, track_id: &str, sender: RtcRtpSender) {
+        let limits = self.limits.get().with_bitrate_cap(self.auto_bitrate.get());
+        let layers = self.simulcast.states(self.requested.borrow().get(track_id));
+        spawn_local(async move {
+            if let Err(e) = apply_limits(sender, limits, layers).await {
+                console::error_1(&e.into());
+            }
+        });
+    }
+
+    // Only video is capped, audio is cheap and the first to suffer
+    pub fn apply(&self) {
+        for (track_id, sender) in self.senders.borrow().iter() {
+            if is_video(sender) {
+                self.apply_sender(track_id, sender.clone());
+            }
+        }
+    }
+}
@@ -0,0 +1,194 @@
+use wasm_bindgen::prelude::*;
+use web_sys::*;
+use wasm_bindgen::JsCast;
+use crate::set;
+use crate::error::Error;
+use crate::connection_stream::control::MediaState;
+
+// Either a bare value or the ideal/min/max/exact form of a constrainable property
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum Constrain<T> {
+    Value(T),
+    Range {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ideal: Option<T>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        min: Option<T>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max: Option<T>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        exact: Option<T>,
+    },
+}
+
+impl<T> Constrain<T> {
+    pub fn exact(value: T) -> Constrain<T> {
+        Constrain::Range { ideal: None, min: None, max: None, exact: Some(value) }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct VideoConstraints {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    width: Option<Constrain<u32>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    height: Option<Constrain<u32>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    frame_rate: Option<Constrain<f64>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    aspect_ratio: Option<Constrain<f64>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    facing_mode: Option<Constrain<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_id: Option<Constrain<String>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct AudioConstraints {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    echo_cancellation: Option<Constrain<bool>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    noise_suppression: Option<Constrain<bool>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    auto_gain_control: Option<Constrain<bool>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    channel_count: Option<Constrain<u32>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sample_rate: Option<Constrain<u32>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_id: Option<Constrain<String>>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum TrackConstraints<T> {
+    Enabled(bool),
+    Constraints(T),
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct CaptureOptions {
+    pub video: TrackConstraints<VideoConstraints>,
+    pub audio: TrackConstraints<AudioConstraints>,
+}
+
+impl Default for CaptureOptions {
+    fn default() -> CaptureOptions {
+        CaptureOptions {
+            video: TrackConstraints::Enabled(true),
+            audio: TrackConstraints::Enabled(true),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ScreenShareOptions {
+    // Send the screen in place of the camera instead of as an extra stream
+    #[serde(skip_serializing)]
+    pub replace_camera: bool,
+    video: TrackConstraints<VideoConstraints>,
+    audio: bool,
+}
+
+impl Default for ScreenShareOptions {
+    fn default() -> ScreenShareOptions {
+        ScreenShareOptions {
+            replace_camera: false,
+            video: TrackConstraints::Enabled(true),
+            audio: false,
+        }
+    }
+}
+
+impl ScreenShareOptions {
+    pub fn from_js(value: JsValue) -> Result<ScreenShareOptions, Error> {
+        if value.is_undefined() || value.is_null() {
+            return Ok(ScreenShareOptions::default());
+        }
+        Ok(serde_wasm_bindgen::from_value(value)?)
+    }
+
+    pub fn to_constraints(&self) -> Result<DisplayMediaStreamConstraints, Error> {
+        Ok(serde_wasm_bindgen::to_value(self)?.unchecked_into())
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum DeviceKind {
+    Camera,
+    Microphone,
+}
+
+impl DeviceKind {
+    pub fn track_kind(self) -> &'static str {
+        match self {
+            DeviceKind::Camera => "video",
+            DeviceKind::Microphone => "audio",
+        }
+    }
+}
+
+impl CaptureOptions {
+    pub fn from_js(value: JsValue) -> Result<CaptureOptions, Error> {
+        if value.is_undefined() || value.is_null() {
+            return Ok(CaptureOptions::default());
+        }
+        Ok(serde_wasm_bindgen::from_value(value)?)
+    }
+
+    // Keeps the constraints of the device kind, pinned to `device_id`, and drops the other kind
+    pub fn with_device(&self, kind: DeviceKind, device_id: String) -> CaptureOptions {
+        match kind {
+            DeviceKind::Camera => {
+                let mut video = match &self.video {
+                    TrackConstraints::Constraints(video) => video.clone(),
+                    TrackConstraints::Enabled(_) => VideoConstraints::default()
+                };
+                video.device_id = Some(Constrain::exact(device_id));
+                CaptureOptions { video: TrackConstraints::Constraints(video), audio: TrackConstraints::Enabled(false) }
+            }
+            DeviceKind::Microphone => {
+                let mut audio = match &self.audio {
+                    TrackConstraints::Constraints(audio) => audio.clone(),
+                    TrackConstraints::Enabled(_) => AudioConstraints::default()
+                };
+                audio.device_id = Some(Constrain::exact(device_id));
+                CaptureOptions { video: TrackConstraints::Enabled(false), audio: TrackConstraints::Constraints(audio) }
+            }
+        }
+    }
+
+    pub fn to_constraints(&self) -> Result<MediaStreamConstraints, Error> {
+        Ok(serde_wasm_bindgen::to_value(self)?.unchecked_into())
+    }
+}
+
+// The stream along with the settings the browser actually applied to its tracks
+pub fn capture_result(stream: &MediaStream) -> JsValue {
+    let settings = js_sys::Object::new();
+    let video = stream.get_video_tracks().get(0).dyn_into::<MediaStreamTrack>()
+        .map(|track| JsValue::from(track.get_settings()))
+        .unwrap_or(JsValue::NULL);
+    let audio = stream.get_audio_tracks().get(0).dyn_into::<MediaStreamTrack>()
+        .map(|track| JsValue::from(track.get_settings()))
+        .unwrap_or(JsValue::NULL);
+    set![settings => "stream", stream.clone()];
+    set![settings => "video", video];
+    set![settings => "audio", audio];
+    settings.into()
+}
+
+// Disabled tracks keep their sender, peers receive silence or black frames
+pub fn apply_media_state(stream: &MediaStream, media: MediaState) {
+    for track in stream.get_audio_tracks().iter() {
+        track.unchecked_into::<MediaStreamTrack>().set_enabled(media.audio);
+    }
+    for track in stream.get_video_tracks().iter() {
+        track.unchecked_into::<MediaStreamTrack>().set_enabled(media.video);
+    }
+}
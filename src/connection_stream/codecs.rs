@@ -0,0 +1,304 @@
+use wasm_bindgen::prelude::*;
+use web_sys::*;
+use wasm_bindgen::JsCast;
+use std::collections::HashMap;
+use crate::get;
+
+// Applied to the `a=fmtp` line of Opus, None keeps the browser's default
+#[derive(Deserialize, Clone, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct OpusOptions {
+    pub stereo: Option<bool>,
+    pub dtx: Option<bool>,
+    pub fec: Option<bool>,
+    pub max_average_bitrate: Option<u32>,
+}
+
+impl OpusOptions {
+    fn params(&self) -> Vec<(&'static str, String)> {
+        let flag = |enabled: bool| if enabled { "1" } else { "0" }.to_string();
+        let mut params = vec![];
+        if let Some(stereo) = self.stereo {
+            params.push(("stereo", flag(stereo)));
+            params.push(("sprop-stereo", flag(stereo)));
+        }
+        if let Some(dtx) = self.dtx {
+            params.push(("usedtx", flag(dtx)));
+        }
+        if let Some(fec) = self.fec {
+            params.push(("useinbandfec", flag(fec)));
+        }
+        if let Some(bitrate) = self.max_average_bitrate {
+            params.push(("maxaveragebitrate", bitrate.to_string()));
+        }
+        params
+    }
+}
+
+// Codec names in order of preference, e.g. ["H264", "VP8"], the codecs that
+// are not listed keep their order after the listed ones
+#[derive(Deserialize, Clone, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct CodecOptions {
+    pub video: Vec<String>,
+    pub audio: Vec<String>,
+    pub opus: OpusOptions,
+}
+
+// "video/H264", "h264" and "H264" all name H264
+fn codec_name(name: &str) -> String {
+    name.rsplit('/').next().unwrap_or(name).to_uppercase()
+}
+
+fn rank(preferred: &[String], name: &str) -> usize {
+    let name = codec_name(name);
+    preferred.iter().position(|codec| codec_name(codec) == name).unwrap_or(preferred.len())
+}
+
+impl CodecOptions {
+    fn preferred(&self, kind: &str) -> &[String] {
+        match kind {
+            "video" => &self.video,
+            _ => &self.audio,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.video.is_empty() && self.audio.is_empty() && self.opus.params().is_empty()
+    }
+
+    // Returns false when the browser lacks setCodecPreferences, the SDP is munged then
+    pub fn set_codec_preferences(&self, peer: &RtcPeerConnection) -> bool {
+        for transceiver in peer.get_transceivers().iter() {
+            let transceiver: RtcRtpTransceiver = transceiver.unchecked_into();
+            let set_codec_preferences = get![transceiver => "setCodecPreferences"];
+            if !set_codec_preferences.is_function() {
+                return false;
+            }
+            let kind = transceiver.receiver().track().kind();
+            let preferred = self.preferred(&kind);
+            let capabilities = match RtcRtpReceiver::get_capabilities(&kind) {
+                Some(capabilities) if !preferred.is_empty() && !transceiver.stopped() => capabilities,
+                _ => continue
+            };
+            let mut codecs: Vec<JsValue> = capabilities.get_codecs().iter().collect();
+            codecs.sort_by_key(|codec| rank(preferred, &get![codec => "mimeType"].as_string().unwrap_or_default()));
+            let codecs: js_sys::Array = codecs.into_iter().collect();
+            let set_codec_preferences: js_sys::Function = set_codec_preferences.unchecked_into();
+            if let Err(e) = set_codec_preferences.call1(&transceiver, &codecs) {
+                console::error_1(&e);
+            }
+        }
+        true
+    }
+
+    pub fn munge(&self, sdp: &str, reorder: bool) -> String {
+        let mut sections = split_sections(sdp);
+        for section in sections.iter_mut().skip(1) {
+            let kind = section[0].trim_start_matches("m=").split(' ').next().unwrap_or("").to_string();
+            if reorder && !self.preferred(&kind).is_empty() {
+                reorder_payloads(section, self.preferred(&kind));
+            }
+            if kind == "audio" {
+                set_opus_params(section, &self.opus.params());
+            }
+        }
+        let mut lines: Vec<String> = sections.into_iter().flatten().collect();
+        lines.push(String::new());
+        lines.join("\r\n")
+    }
+}
+
+// The session part, then one section per m= line
+fn split_sections(sdp: &str) -> Vec<Vec<String>> {
+    let mut sections = vec![vec![]];
+    for line in sdp.split("\r\n").filter(|line| !line.is_empty()) {
+        if line.starts_with("m=") {
+            sections.push(vec![]);
+        }
+        sections.last_mut().unwrap().push(line.to_string());
+    }
+    sections
+}
+
+// Payload type => codec name, from the a=rtpmap lines of a section
+fn rtpmap(section: &[String]) -> HashMap<String, String> {
+    section.iter()
+        .filter_map(|line| line.strip_prefix("a=rtpmap:"))
+        .filter_map(|map| {
+            let (payload, codec) = map.split_once(' ')?;
+            Some((payload.to_string(), codec.split('/').next()?.to_string()))
+        })
+        .collect()
+}
+
+fn reorder_payloads(section: &mut [String], preferred: &[String]) {
+    let codecs = rtpmap(section);
+    // RTX is ranked with the codec it retransmits
+    let apt: HashMap<String, String> = section.iter()
+        .filter_map(|line| line.strip_prefix("a=fmtp:"))
+        .filter_map(|fmtp| {
+            let (payload, params) = fmtp.split_once(' ')?;
+            let apt = params.split(';').find_map(|param| param.trim().strip_prefix("apt="))?;
+            Some((payload.to_string(), apt.to_string()))
+        })
+        .collect();
+    let payload_rank = |payload: &String| {
+        let payload = apt.get(payload).unwrap_or(payload);
+        codecs.get(payload).map(|codec| rank(preferred, codec)).unwrap_or(preferred.len())
+    };
+    // m=<kind> <port> <proto> <payload types...>
+    let mut fields: Vec<String> = section[0].split(' ').map(String::from).collect();
+    if fields.len() <= 3 {
+        return;
+    }
+    let mut payloads = fields.split_off(3);
+    payloads.sort_by_key(payload_rank);
+    fields.extend(payloads);
+    section[0] = fields.join(" ");
+}
+
+fn set_opus_params(section: &mut Vec<String>, params: &[(&'static str, String)]) {
+    if params.is_empty() {
+        return;
+    }
+    let payload = match rtpmap(section).into_iter().find(|(_, codec)| codec.eq_ignore_ascii_case("opus")) {
+        Some((payload, _)) => payload,
+        None => return
+    };
+    let prefix = format!("a=fmtp:{} ", payload);
+    let mut fmtp: Vec<(String, String)> = vec![];
+    let position = section.iter().position(|line| line.starts_with(&prefix));
+    if let Some(position) = position {
+        fmtp = section[position][prefix.len()..].split(';')
+            .filter_map(|param| param.trim().split_once('='))
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+    }
+    for (key, value) in params {
+        match fmtp.iter_mut().find(|(existing, _)| existing == key) {
+            Some(param) => param.1 = value.clone(),
+            None => fmtp.push((key.to_string(), value.clone()))
+        }
+    }
+    let params: Vec<String> = fmtp.iter().map(|(key, value)| format!("{}={}", key, value)).collect();
+    let line = format!("{}{}", prefix, params.join(";"));
+    match position {
+        Some(position) => section[position] = line,
+        None => {
+            let rtpmap = format!("a=rtpmap:{} ", payload);
+            let after = section.iter().position(|line| line.starts_with(&rtpmap)).map(|i| i + 1).unwrap_or(section.len());
+            section.insert(after, line);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Trimmed from a Chrome offer
+    const CHROME_SDP: &str = concat!(
+        "v=0\r\n",
+        "o=- 4611731400430051336 2 IN IP4 127.0.0.1\r\n",
+        "s=-\r\n",
+        "t=0 0\r\n",
+        "a=group:BUNDLE 0 1\r\n",
+        "m=audio 9 UDP/TLS/RTP/SAVPF 111 63 9 0 8\r\n",
+        "c=IN IP4 0.0.0.0\r\n",
+        "a=mid:0\r\n",
+        "a=sendrecv\r\n",
+        "a=rtpmap:111 opus/48000/2\r\n",
+        "a=rtcp-fb:111 transport-cc\r\n",
+        "a=fmtp:111 minptime=10;useinbandfec=1\r\n",
+        "a=rtpmap:63 red/48000/2\r\n",
+        "a=fmtp:63 111/111\r\n",
+        "a=rtpmap:9 G722/8000\r\n",
+        "a=rtpmap:0 PCMU/8000\r\n",
+        "a=rtpmap:8 PCMA/8000\r\n",
+        "m=video 9 UDP/TLS/RTP/SAVPF 96 97 102 103 45 46\r\n",
+        "c=IN IP4 0.0.0.0\r\n",
+        "a=mid:1\r\n",
+        "a=sendrecv\r\n",
+        "a=rtpmap:96 VP8/90000\r\n",
+        "a=rtcp-fb:96 nack\r\n",
+        "a=rtpmap:97 rtx/90000\r\n",
+        "a=fmtp:97 apt=96\r\n",
+        "a=rtpmap:102 H264/90000\r\n",
+        "a=rtcp-fb:102 nack\r\n",
+        "a=fmtp:102 level-asymmetry-allowed=1;packetization-mode=1;profile-level-id=42001f\r\n",
+        "a=rtpmap:103 rtx/90000\r\n",
+        "a=fmtp:103 apt=102\r\n",
+        "a=rtpmap:45 AV1/90000\r\n",
+        "a=rtpmap:46 rtx/90000\r\n",
+        "a=fmtp:46 apt=45\r\n",
+    );
+
+    // Trimmed from a Firefox offer, without the fmtp line of Opus
+    const FIREFOX_SDP: &str = concat!(
+        "v=0\r\n",
+        "o=mozilla...THIS_IS_SDPARTA-99.0 5041734421474398216 0 IN IP4 0.0.0.0\r\n",
+        "s=-\r\n",
+        "t=0 0\r\n",
+        "m=audio 9 UDP/TLS/RTP/SAVPF 109 9 0 8 101\r\n",
+        "c=IN IP4 0.0.0.0\r\n",
+        "a=mid:0\r\n",
+        "a=rtpmap:109 opus/48000/2\r\n",
+        "a=rtpmap:9 G722/8000/1\r\n",
+        "a=rtpmap:0 PCMU/8000\r\n",
+        "a=rtpmap:8 PCMA/8000\r\n",
+        "a=rtpmap:101 telephone-event/8000/1\r\n",
+        "a=fmtp:101 0-15\r\n",
+    );
+
+    fn line<'a>(sdp: &'a str, prefix: &str) -> &'a str {
+        sdp.split("\r\n").find(|line| line.starts_with(prefix)).unwrap()
+    }
+
+    #[test]
+    fn puts_the_preferred_codec_first() {
+        let options = CodecOptions { video: vec!["video/H264".to_string()], ..Default::default() };
+        let sdp = options.munge(CHROME_SDP, true);
+        assert_eq!(line(&sdp, "m=video"), "m=video 9 UDP/TLS/RTP/SAVPF 102 103 96 97 45 46");
+        // Audio has no preference and keeps its order
+        assert_eq!(line(&sdp, "m=audio"), "m=audio 9 UDP/TLS/RTP/SAVPF 111 63 9 0 8");
+    }
+
+    #[test]
+    fn rtx_follows_the_codec_it_retransmits() {
+        let options = CodecOptions { video: vec!["AV1".to_string(), "VP8".to_string()], ..Default::default() };
+        let sdp = options.munge(CHROME_SDP, true);
+        assert_eq!(line(&sdp, "m=video"), "m=video 9 UDP/TLS/RTP/SAVPF 45 46 96 97 102 103");
+    }
+
+    #[test]
+    fn payloads_stay_put_without_reorder() {
+        let options = CodecOptions { video: vec!["H264".to_string()], ..Default::default() };
+        assert_eq!(options.munge(CHROME_SDP, false), CHROME_SDP);
+    }
+
+    #[test]
+    fn merges_opus_params_into_the_existing_fmtp() {
+        let options = CodecOptions {
+            opus: OpusOptions { stereo: Some(true), fec: Some(false), ..Default::default() },
+            ..Default::default()
+        };
+        let sdp = options.munge(CHROME_SDP, false);
+        assert_eq!(line(&sdp, "a=fmtp:111"), "a=fmtp:111 minptime=10;useinbandfec=0;stereo=1;sprop-stereo=1");
+        assert_eq!(sdp.matches("a=fmtp:111").count(), 1);
+        // Other audio payloads are left alone
+        assert_eq!(line(&sdp, "a=fmtp:63"), "a=fmtp:63 111/111");
+    }
+
+    #[test]
+    fn adds_an_opus_fmtp_after_its_rtpmap() {
+        let options = CodecOptions {
+            opus: OpusOptions { dtx: Some(true), max_average_bitrate: Some(32000), ..Default::default() },
+            ..Default::default()
+        };
+        let sdp = options.munge(FIREFOX_SDP, false);
+        let lines: Vec<&str> = sdp.split("\r\n").collect();
+        let rtpmap = lines.iter().position(|line| *line == "a=rtpmap:109 opus/48000/2").unwrap();
+        assert_eq!(lines[rtpmap + 1], "a=fmtp:109 usedtx=1;maxaveragebitrate=32000");
+    }
+}
@@ -1,198 +1,489 @@
 use wasm_bindgen::prelude::*;
 use web_sys::*;
 use wasm_bindgen::JsCast;
-use wasm_bindgen_futures::future_to_promise;
+use wasm_bindgen_futures::{future_to_promise, spawn_local};
 use crate::js_extend::ConnectionOffer;
-use crate::{js_await, get};
+use crate::{js_await, get, set};
 use std::rc::Rc;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
 use crate::connection_stream::render_video::{create_video, VideoRenderer};
-
-#[derive(Serialize)]
-pub struct StunServer {
-    urls: Vec<&'static str>,
-}
-
-#[derive(Serialize)]
-pub struct TurnServer {
-    urls: Vec<&'static str>,
-    credential: &'static str,
-    username: &'static str,
-}
+use crate::connection_stream::ice::{CandidateQueue, IceConfig};
+use crate::connection_stream::negotiation::Negotiator;
+use crate::connection_stream::reconnect::Reconnector;
+use crate::connection_stream::events::Events;
+use crate::connection_stream::options::StreamingOptions;
+use crate::connection_stream::control::{ControlChannel, ControlMessage, MediaState};
+use crate::connection_stream::data::DataChannel;
+use crate::connection_stream::transfer::{FileTransfer, FileTransfers};
+use crate::connection_stream::stats::StatsSampler;
+use crate::connection_stream::bandwidth::{SendLimits, Senders};
+use crate::connection_stream::simulcast::Simulcast;
+use crate::error::Error;
 
 pub struct Connection {
+    id: String,
     peer: Rc<RtcPeerConnection>,
-    on_ice_candidate: js_sys::Function,
-    video: Rc<web_sys::HtmlVideoElement>,
-    _on_state_change: Closure<dyn FnMut(JsValue)>
+    on_ice_candidate: Rc<RefCell<js_sys::Function>>,
+    ice: Rc<IceConfig>,
+    candidates: Rc<CandidateQueue>,
+    negotiator: Rc<Negotiator>,
+    senders: Rc<Senders>,
+    events: Rc<Events>,
+    // Remote track id => ids of the streams it belongs to
+    remote_tracks: Rc<RefCell<HashMap<String, Vec<String>>>>,
+    renderer: Rc<RefCell<VideoRenderer>>,
+    control: ControlChannel,
+    data: DataChannel,
+    transfers: Rc<FileTransfers>,
+    stats: Rc<StatsSampler>,
+    // Last size sent to the peer, sent again once the control channel opens
+    tile_size: Rc<Cell<Option<(f64, f64)>>>,
+    _callbacks: Vec<Closure<dyn FnMut(JsValue)>>,
 }
 
 impl Connection {
-    fn create_config() -> RtcConfiguration {
-        let mut config = RtcConfiguration::new();
-        let arr = js_sys::Array::new();
-        let stun = StunServer {
-            urls: vec!["stun:stun.l.google.com:19302"]
-        };
-        let turn = TurnServer {
-            urls: vec!["turn:numb.viagenie.ca"],
-            credential: "muazkh",
-            username: "webrtc@live.com",
-        };
-        arr.push(&JsValue::from_serde(&stun).unwrap());
-        arr.push(&JsValue::from_serde(&turn).unwrap());
-        config.ice_servers(&arr);
-        config
+    // Forwards a peer state change to the JS listeners of `name`
+    fn state_event_cb(&self, name: &'static str, property: &'static str,
+                      on_change: Option<Box<dyn Fn()>>) -> Closure<dyn FnMut(JsValue)> {
+        let peer = self.peer.clone();
+        let events = self.events.clone();
+        let id = self.id.clone();
+        Closure::wrap(Box::new(move |_event: JsValue| {
+            let detail = js_sys::Object::new();
+            set![detail => "state", get![peer => property]];
+            events.emit(name, &id, &detail);
+            if let Some(on_change) = &on_change {
+                on_change();
+            }
+        }) as Box<dyn FnMut(JsValue)>)
     }
 
-    fn state_change_cb(on_state: Box<dyn Fn()>) -> Closure<dyn FnMut(JsValue)> {
+    // Shared by the `ended` event of remote tracks and `removetrack` of remote streams,
+    // a stream's tile goes away with its last track
+    fn track_removed_cb(&self) -> Closure<dyn FnMut(JsValue)> {
+        let events = self.events.clone();
+        let id = self.id.clone();
+        let remote_tracks = self.remote_tracks.clone();
+        let renderer = self.renderer.clone();
         Closure::wrap(Box::new(move |event: JsValue| {
-            let js_state = get![event => "target" => "iceConnectionState"];
-            match js_state.as_string() {
-                None => {
-                    console::error_1(&event);
-                    panic!("Invalid string");
-                }
-                Some(state) if state == "failed" || state == "disconnected" || state == "closed" => {
-                    (on_state)();
+            let track = match get![event => "track"] {
+                track if track.is_object() => track,
+                _ => get![event => "target"]
+            };
+            let track: MediaStreamTrack = track.unchecked_into();
+            let removed = remote_tracks.borrow_mut().remove(&track.id());
+            let stream_ids = match removed {
+                Some(stream_ids) => stream_ids,
+                None => return
+            };
+            for stream_id in stream_ids {
+                if !remote_tracks.borrow().values().any(|ids| ids.contains(&stream_id)) {
+                    renderer.borrow_mut().remove_video(&(id.clone(), stream_id));
                 }
-                Some(_) => {}
             }
+            let detail = js_sys::Object::new();
+            set![detail => "track", track];
+            events.emit("trackremoved", &id, &detail);
         }) as Box<dyn FnMut(JsValue)>)
     }
 
-    pub fn new(id: String, renderer: &mut VideoRenderer, on_state: Box<dyn Fn()>) -> Connection {
-        let video = create_video(false).unwrap();
-        renderer.add_video(id.as_str().into(), video.clone());
-        let _on_state_change = Connection::state_change_cb(on_state);
-        let config = Connection::create_config();
-        let raw_peer = RtcPeerConnection::new_with_configuration(&config).unwrap();
-        raw_peer.set_oniceconnectionstatechange(_on_state_change.as_ref().dyn_ref());
+    fn data_channel_cb(&self) -> Closure<dyn FnMut(JsValue)> {
+        let transfers = self.transfers.clone();
+        Closure::wrap(Box::new(move |event: JsValue| {
+            let channel: RtcDataChannel = get![event => "channel"].unchecked_into();
+            if !transfers.receive(channel.clone()) {
+                console::warn_2(&"Unexpected data channel".into(), &channel.label().into());
+            }
+        }) as Box<dyn FnMut(JsValue)>)
+    }
+
+    fn add_tile(renderer: &RefCell<VideoRenderer>, id: &str, stream: &MediaStream) -> Result<(), JsValue> {
+        let tile = (id.to_string(), stream.id());
+        let mut renderer = renderer.borrow_mut();
+        if renderer.has_video(&tile) {
+            return Ok(());
+        }
+        let video = create_video(false)?;
+        video.set_src_object(Some(stream));
+        let _ = video.play();
+        // The first stream of a peer is taken as its camera
+        let camera = !renderer.has_camera(id);
+        renderer.add_video(tile, video, camera);
+        Ok(())
+    }
+
+    fn negotiation_needed_cb(negotiator: Rc<Negotiator>) -> Closure<dyn FnMut(JsValue)> {
+        Closure::wrap(Box::new(move |_event: JsValue| {
+            let negotiator = negotiator.clone();
+            spawn_local(async move {
+                if let Err(e) = negotiator.negotiation_needed().await {
+                    console::error_1(&e.into());
+                }
+            });
+        }) as Box<dyn FnMut(JsValue)>)
+    }
+
+    fn control_message_cb(id: String, renderer: Rc<RefCell<VideoRenderer>>, events: Rc<Events>,
+                          senders: Rc<Senders>) -> Box<dyn Fn(ControlMessage)> {
+        Box::new(move |message: ControlMessage| match message {
+            ControlMessage::Media(media) => {
+                renderer.borrow_mut().set_media_state(&id, media);
+                match serde_wasm_bindgen::to_value(&media) {
+                    Ok(detail) => events.emit("mediastate", &id, &detail),
+                    Err(e) => console::error_1(&e.into())
+                }
+            }
+            ControlMessage::Layers { rids } => senders.request_layers(rids),
+            ControlMessage::TileSize { width, height } => senders.fit_tile(width, height),
+        })
+    }
+
+    // Messages sent before the channel opened are dropped, the current state is sent instead
+    fn control_open_cb(media: Rc<Cell<MediaState>>, tile_size: Rc<Cell<Option<(f64, f64)>>>) -> Box<dyn Fn() -> Vec<ControlMessage>> {
+        Box::new(move || {
+            let mut messages = vec![ControlMessage::Media(media.get())];
+            if let Some((width, height)) = tile_size.get() {
+                messages.push(ControlMessage::TileSize { width, height });
+            }
+            messages
+        })
+    }
+
+    pub fn new(id: String, renderer: Rc<RefCell<VideoRenderer>>, on_state: Box<dyn Fn()>, ice: Rc<IceConfig>,
+               events: Rc<Events>, options: Rc<StreamingOptions>, media: Rc<Cell<MediaState>>) -> Result<Connection, JsValue> {
+        let raw_peer = RtcPeerConnection::new_with_configuration(&ice.create_config()?)?;
         let peer: Rc<RtcPeerConnection> = Rc::new(raw_peer);
-        Connection {
-            video,
+        let candidates = Rc::new(CandidateQueue::default());
+        let negotiator = Rc::new(Negotiator::new(
+            peer.clone(), options.polite(&id), candidates.clone(), ice.clone(), options.codecs.clone(),
+        ));
+        let reconnector = Rc::new(Reconnector::new(
+            id.clone(), peer.clone(), negotiator.clone(), ice.clone(), events.clone(), options.reconnect, on_state,
+        ));
+        let senders = Rc::new(Senders::new(Simulcast::new(&options.simulcast)));
+        let tile_size = Rc::new(Cell::new(None));
+        let control = ControlChannel::new(
+            &peer,
+            Connection::control_open_cb(media, tile_size.clone()),
+            Connection::control_message_cb(id.clone(), renderer.clone(), events.clone(), senders.clone()),
+        );
+        let data = DataChannel::new(id.clone(), &peer, options.data_channel, events.clone());
+        let transfers = Rc::new(FileTransfers::new(id.clone(), events.clone()));
+        let quality_renderer = renderer.clone();
+        let quality_id = id.clone();
+        let stats = Rc::new(StatsSampler::new(id.clone(), peer.clone(), events.clone(), Box::new(move |quality| {
+            quality_renderer.borrow_mut().set_quality(&quality_id, Some(quality));
+        })));
+        let mut connection = Connection {
+            id,
+            stats,
+            tile_size,
+            control,
+            data,
+            transfers,
+            renderer,
             peer,
-            on_ice_candidate: js_sys::Function::new_no_args(""),
-            _on_state_change
+            on_ice_candidate: Rc::new(RefCell::new(js_sys::Function::new_no_args(""))),
+            ice,
+            candidates,
+            negotiator,
+            senders,
+            events,
+            remote_tracks: Rc::new(RefCell::new(HashMap::new())),
+            _callbacks: vec![],
+        };
+        let on_ice_state = connection.state_event_cb("iceconnectionstatechange", "iceConnectionState",
+                                                     Some(Box::new(move || reconnector.on_state_change())));
+        let on_connection_state = connection.state_event_cb("connectionstatechange", "connectionState", None);
+        let stable_peer = connection.peer.clone();
+        let stable_senders = connection.senders.clone();
+        let stable_negotiator = connection.negotiator.clone();
+        // Caps set before negotiation found no encodings, they are applied again once it's done.
+        // Track changes made while an offer was in flight are offered now.
+        let on_stable = Box::new(move || {
+            if stable_peer.signaling_state() != RtcSignalingState::Stable {
+                return;
+            }
+            stable_senders.apply();
+            if stable_negotiator.take_pending() {
+                let negotiator = stable_negotiator.clone();
+                spawn_local(async move {
+                    if let Err(e) = negotiator.renegotiate().await {
+                        console::error_1(&e.into());
+                    }
+                });
+            }
+        });
+        let on_signaling_state = connection.state_event_cb("signalingstatechange", "signalingState", Some(on_stable));
+        let on_gathering_state = connection.state_event_cb("icegatheringstatechange", "iceGatheringState", None);
+        let on_ice_candidate = connection.ice_candidate_cb();
+        let on_track_removed = connection.track_removed_cb();
+        let on_track = connection.track_cb(on_track_removed.as_ref().unchecked_ref::<js_sys::Function>().clone());
+        let on_data_channel = connection.data_channel_cb();
+        let peer = &connection.peer;
+        peer.set_oniceconnectionstatechange(on_ice_state.as_ref().dyn_ref());
+        peer.set_onconnectionstatechange(on_connection_state.as_ref().dyn_ref());
+        peer.set_onsignalingstatechange(on_signaling_state.as_ref().dyn_ref());
+        peer.set_onicegatheringstatechange(on_gathering_state.as_ref().dyn_ref());
+        peer.set_onicecandidate(on_ice_candidate.as_ref().dyn_ref());
+        peer.set_ontrack(on_track.as_ref().dyn_ref());
+        peer.set_ondatachannel(on_data_channel.as_ref().dyn_ref());
+        connection._callbacks = vec![
+            on_ice_state, on_connection_state, on_signaling_state, on_gathering_state, on_ice_candidate, on_track_removed, on_track,
+            on_data_channel,
+        ];
+        if connection.is_perfect() {
+            let on_negotiation_needed = Connection::negotiation_needed_cb(connection.negotiator.clone());
+            connection.peer.set_onnegotiationneeded(on_negotiation_needed.as_ref().dyn_ref());
+            connection._callbacks.push(on_negotiation_needed);
         }
+        Ok(connection)
     }
 
-    pub fn create_offer(&self, stream: &MediaStream) -> ConnectionOffer {
-        let peer = Rc::clone(&self.peer);
+    pub fn is_perfect(&self) -> bool {
+        self.negotiator.is_perfect()
+    }
 
-        let on_track = self.track_cb();
+    pub fn close(&self) {
+        self.candidates.reject_all(Error::PeerClosed(self.id.clone()));
+        self.transfers.cancel_all();
+        self.data.close();
+        self.control.close();
+        self.peer.close();
+    }
 
-        let cb1 = self.ice_candidate_cb();
+    pub fn send_control(&self, message: &ControlMessage) -> Result<(), Error> {
+        self.control.send(message)
+    }
 
-        peer.set_onicecandidate(cb1.as_ref().dyn_ref());
+    pub fn send(&self, data: &JsValue) -> Result<(), Error> {
+        self.data.send(data)
+    }
 
-        peer.set_ontrack(on_track.as_ref().dyn_ref());
+    pub fn send_file(&self, file: Blob, name: Option<String>) -> FileTransfer {
+        self.transfers.send(&self.peer, file, name)
+    }
 
-        stream.get_tracks().iter().for_each(|track: JsValue| {
-            peer.add_track_0(&track.unchecked_into(), &stream);
-        });
+    pub fn cancel_transfer(&self, transfer_id: &str) -> bool {
+        self.transfers.cancel(transfer_id)
+    }
 
-        let p = future_to_promise(async move {
-            let mut options: RtcOfferOptions = RtcOfferOptions::new();
-            options.offer_to_receive_audio(true);
-            options.offer_to_receive_video(true);
-            let promise = peer.create_offer_with_rtc_offer_options(&options);
+    pub fn get_stats(&self) -> js_sys::Promise {
+        let stats = self.stats.clone();
+        future_to_promise(async move {
+            Ok(stats.sample().await?)
+        })
+    }
 
-            let js_offer: JsValue = js_await![promise].unwrap();
-            let offer: RtcSessionDescriptionInit = js_offer.unchecked_into();
-            let set_local_promise = peer.as_ref().set_local_description(&offer);
-            js_await![set_local_promise].unwrap();
-            Ok(offer.unchecked_into())
+    pub fn sample_stats(&self) {
+        let stats = self.stats.clone();
+        spawn_local(async move {
+            if let Err(e) = stats.sample().await {
+                console::error_1(&e.into());
+            }
         });
+    }
 
-        let mut cb_ret = ConnectionOffer::new(p);
-        cb_ret.add_cb(on_track);
-        cb_ret.add_cb(cb1);
-        cb_ret
+    pub fn set_configuration(&self, config: &RtcConfiguration) -> Result<(), JsValue> {
+        self.peer.set_configuration_with_configuration(config)
     }
 
-    pub fn accept_offer(&self, offer: RtcSessionDescriptionInit, stream: &MediaStream) -> ConnectionOffer {
+    pub fn create_offer(&self, stream: &MediaStream) -> ConnectionOffer {
         let peer = Rc::clone(&self.peer);
+        let negotiator = Rc::clone(&self.negotiator);
 
-        let on_track = self.track_cb();
+        self.negotiator.set_offerer();
+        self.add_stream(stream, false);
 
-        let cb1 = self.ice_candidate_cb();
-        peer.set_onicecandidate(cb1.as_ref().dyn_ref());
+        let p = future_to_promise(async move {
+            let options: RtcOfferOptions = RtcOfferOptions::new();
+            options.set_offer_to_receive_audio(true);
+            options.set_offer_to_receive_video(true);
+            let offer = negotiator.create_offer(&options).await?;
+            let set_local_promise = peer.as_ref().set_local_description(&offer);
+            js_await![set_local_promise].map_err(Error::SdpRejected)?;
+            Ok(offer.unchecked_into())
+        });
 
-        peer.set_ontrack(on_track.as_ref().dyn_ref());
+        ConnectionOffer::new(p)
+    }
 
-        stream.get_tracks().iter().for_each(|track: JsValue| {
-            peer.add_track_0(&track.unchecked_into(), &stream);
-        });
+    // `on_answered` runs once the answer is set, tracks the offer had no section
+    // for can only be offered from then on
+    pub fn accept_offer(&self, offer: RtcSessionDescriptionInit, stream: &MediaStream,
+                        on_answered: Box<dyn FnOnce()>) -> ConnectionOffer {
+        let peer = Rc::clone(&self.peer);
+        let candidates = Rc::clone(&self.candidates);
+        let negotiator = Rc::clone(&self.negotiator);
+
+        self.add_stream(stream, true);
 
         let p = future_to_promise(async move {
-            let set_remote_promise = peer.as_ref().set_remote_description(&offer);
-            js_await![set_remote_promise].unwrap();
+            candidates.set_remote(&peer, &offer).await?;
 
-            let answer_promise = peer.as_ref().create_answer();
-            let js_answer: JsValue = js_await![answer_promise].unwrap();
-            let answer: RtcSessionDescriptionInit = js_answer.unchecked_into();
-            js_await![peer.as_ref().set_local_description(&answer)].unwrap();
+            let answer = negotiator.create_answer().await?;
+            js_await![peer.as_ref().set_local_description(&answer)].map_err(Error::SdpRejected)?;
+            on_answered();
             Ok(answer.unchecked_into())
         });
 
-        let mut cb_ret = ConnectionOffer::new(p);
-        cb_ret.add_cb(on_track);
-        cb_ret.add_cb(cb1);
-        cb_ret
+        ConnectionOffer::new(p)
     }
 
     pub fn accept_answer(&self, answer: RtcSessionDescriptionInit) -> ConnectionOffer {
         let peer = Rc::clone(&self.peer);
+        let candidates = Rc::clone(&self.candidates);
 
         let p = future_to_promise(async move {
-            let set_remote_promise = peer.as_ref().set_remote_description(&answer);
-            js_await![set_remote_promise].unwrap();
+            candidates.set_remote(&peer, &answer).await?;
             Ok(JsValue::TRUE)
         });
 
-        let cb_ret = ConnectionOffer::new(p);
-        cb_ret
+        ConnectionOffer::new(p)
     }
 
     fn ice_candidate_cb(&self) -> Closure<dyn FnMut(JsValue)> {
         let cb = self.on_ice_candidate.clone();
+        let ice = self.ice.clone();
         Closure::wrap(Box::new(move |event: JsValue| {
-            match get![event => "candidate"].dyn_into::<RtcIceCandidate>() {
-                Ok(candidate) => {
-                    match &get![candidate => "protocol"].as_string() {
-                        None => {}
-                        Some(proto) if proto == "udp" => {
-                            cb.call1(&JsValue::NULL, &candidate).unwrap();
-                        }
-                        _ => {}
-                    }
-                }
-                Err(_e) => {}
+            let result = match get![event => "candidate"].dyn_into::<RtcIceCandidate>() {
+                Ok(candidate) if ice.accepts(&candidate) => cb.borrow().call1(&JsValue::NULL, &candidate),
+                Ok(_) => Ok(JsValue::UNDEFINED),
+                // A null candidate marks the end of gathering
+                Err(_e) => cb.borrow().call1(&JsValue::NULL, &JsValue::NULL)
             };
+            if let Err(e) = result {
+                console::error_1(&e);
+            }
         }) as Box<dyn FnMut(JsValue)>)
     }
 
-    fn track_cb(&self) -> Closure<dyn FnMut(JsValue)> {
-        let video_rc = Rc::clone(&self.video);
+    fn track_cb(&self, on_removed: js_sys::Function) -> Closure<dyn FnMut(JsValue)> {
+        let renderer = self.renderer.clone();
+        let events = self.events.clone();
+        let id = self.id.clone();
+        let remote_tracks = self.remote_tracks.clone();
         Closure::wrap(Box::new(move |event: JsValue| {
-            match video_rc.as_ref().src_object() {
-                Some(_src) => {}
-                None => {
-                    let streams: js_sys::Array = get![event => "streams"].unchecked_into();
-                    let js_stream: JsValue = streams.get(0);
-                    let stream: MediaStream = js_stream.unchecked_into();
-                    video_rc.set_src_object(Some(&stream));
-                    let _ = video_rc.play().unwrap();
+            let track: MediaStreamTrack = get![event => "track"].unchecked_into();
+            let mut streams: js_sys::Array = get![event => "streams"].unchecked_into();
+            // A track sent without a stream still gets a tile of its own
+            if streams.length() == 0 {
+                match MediaStream::new() {
+                    Ok(stream) => {
+                        stream.add_track(&track);
+                        streams = js_sys::Array::of1(&stream);
+                    }
+                    Err(e) => console::error_1(&e)
+                }
+            }
+            track.set_onended(Some(&on_removed));
+            let mut stream_ids = vec![];
+            for stream in streams.iter() {
+                let stream: MediaStream = stream.unchecked_into();
+                stream.set_onremovetrack(Some(&on_removed));
+                if let Err(e) = Connection::add_tile(&renderer, &id, &stream) {
+                    console::error_1(&e);
                 }
+                stream_ids.push(stream.id());
             }
+            remote_tracks.borrow_mut().insert(track.id(), stream_ids);
+            let detail = js_sys::Object::new();
+            set![detail => "track", track];
+            set![detail => "streams", streams];
+            events.emit("trackadded", &id, &detail);
         }) as Box<dyn FnMut(JsValue)>)
     }
 
-    pub fn set_on_ice_candidate(&mut self, closure: js_sys::Function) {
-        self.on_ice_candidate = closure;
+    pub fn set_on_ice_candidate(&self, closure: js_sys::Function) {
+        *self.on_ice_candidate.borrow_mut() = closure;
+    }
+
+    pub fn set_on_description(&self, closure: js_sys::Function) {
+        self.negotiator.set_on_description(closure);
+    }
+
+    // Returns whether the track was not already sent to this peer
+    pub fn add_track(&self, track: &MediaStreamTrack, stream: &MediaStream) -> bool {
+        self.senders.add(&self.peer, track, stream, true)
+    }
+
+    pub fn limits(&self) -> SendLimits {
+        self.senders.limits()
+    }
+
+    pub fn set_limits(&self, limits: SendLimits) {
+        self.senders.set_limits(limits);
+    }
+
+    pub fn set_auto_bitrate(&self, kbps: Option<u32>) {
+        self.senders.set_auto_bitrate(kbps);
+    }
+
+    // Asks the peer to only send these simulcast layers of its video
+    pub fn request_layers(&self, rids: Vec<String>) -> Result<(), Error> {
+        self.control.send(&ControlMessage::Layers { rids })
+    }
+
+    // Size the peer's video is drawn at, it picks its simulcast layer from it
+    pub fn set_tile_size(&self, width: f64, height: f64) -> Result<(), Error> {
+        if self.tile_size.replace(Some((width, height))) == Some((width, height)) {
+            return Ok(());
+        }
+        self.control.send(&ControlMessage::TileSize { width, height })
+    }
+
+    pub fn remove_track(&self, track_id: &str) -> bool {
+        match self.senders.remove(track_id) {
+            Some(sender) => {
+                self.peer.remove_track(&sender);
+                true
+            }
+            None => false
+        }
+    }
+
+    // Swaps the media of a sender in place, no renegotiation is needed
+    pub fn replace_track(&self, old_track_id: &str, track: &MediaStreamTrack) -> Option<js_sys::Promise> {
+        self.senders.replace(old_track_id, track)
+    }
+
+    // The local stream, its video is the camera. Before answering its tracks go on
+    // the transceivers of the remote offer, simulcast is then capped to a single layer.
+    pub fn add_stream(&self, stream: &MediaStream, answering: bool) {
+        stream.get_tracks().iter().for_each(|track: JsValue| {
+            self.senders.add(&self.peer, &track.unchecked_into(), stream, !answering);
+        });
+        if let Ok(camera) = stream.get_video_tracks().get(0).dyn_into::<MediaStreamTrack>() {
+            self.senders.set_camera(&camera.id());
+        }
+    }
+
+    // Perfect negotiation picks up track changes through negotiationneeded
+    pub fn renegotiate(&self) {
+        if self.is_perfect() {
+            return;
+        }
+        let negotiator = self.negotiator.clone();
+        spawn_local(async move {
+            if let Err(e) = negotiator.renegotiate().await {
+                console::error_1(&e.into());
+            }
+        });
+    }
+
+    pub fn handle_description(&self, description: RtcSessionDescriptionInit) -> Result<js_sys::Promise, Error> {
+        if !self.is_perfect() {
+            return Err(Error::NegotiationMode("Perfect negotiation is not enabled, use accept_offer/accept_answer"));
+        }
+        let negotiator = self.negotiator.clone();
+        Ok(future_to_promise(async move {
+            negotiator.handle_description(description).await?;
+            Ok(JsValue::TRUE)
+        }))
     }
 
-    pub fn add_ice_candidate(&mut self, candidate: RtcIceCandidate) -> js_sys::Promise {
-        self.peer.as_ref().add_ice_candidate_with_opt_rtc_ice_candidate(Option::from(&candidate))
+    pub fn add_ice_candidate(&self, candidate: RtcIceCandidate) -> js_sys::Promise {
+        CandidateQueue::add(self.candidates.clone(), self.peer.clone(), candidate)
     }
 }
@@ -0,0 +1,89 @@
+use wasm_bindgen::prelude::*;
+use web_sys::*;
+use wasm_bindgen::JsCast;
+use crate::get;
+use crate::error::Error;
+
+// Both sides create the channel with this id, so it needs no announcement
+const CONTROL_CHANNEL_ID: u16 = 0;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct MediaState {
+    pub audio: bool,
+    pub video: bool,
+}
+
+impl Default for MediaState {
+    fn default() -> MediaState {
+        MediaState { audio: true, video: true }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum ControlMessage {
+    Media(MediaState),
+    // Simulcast layers the receiver wants, by rid
+    Layers { rids: Vec<String> },
+    // Size the receiver draws the video at, the sender picks a layer from it
+    TileSize { width: f64, height: f64 },
+}
+
+impl ControlMessage {
+    fn to_json(&self) -> Result<String, Error> {
+        let value = serde_wasm_bindgen::to_value(self)?;
+        Ok(String::from(js_sys::JSON::stringify(&value)?))
+    }
+
+    fn from_json(json: &str) -> Result<ControlMessage, Error> {
+        Ok(serde_wasm_bindgen::from_value(js_sys::JSON::parse(json)?)?)
+    }
+}
+
+// Pre-negotiated data channel carrying the crate's own messages between peers
+pub struct ControlChannel {
+    channel: RtcDataChannel,
+    _callbacks: Vec<Closure<dyn FnMut(JsValue)>>,
+}
+
+impl ControlChannel {
+    pub fn new(peer: &RtcPeerConnection, on_open: Box<dyn Fn() -> Vec<ControlMessage>>,
+               on_message: Box<dyn Fn(ControlMessage)>) -> ControlChannel {
+        let init = RtcDataChannelInit::new();
+        init.set_negotiated(true);
+        init.set_id(CONTROL_CHANNEL_ID);
+        let channel = peer.create_data_channel_with_data_channel_dict("control", &init);
+        let open_channel = channel.clone();
+        let on_channel_open = Closure::wrap(Box::new(move |_event: JsValue| {
+            for message in on_open() {
+                if let Err(e) = message.to_json().and_then(|json| Ok(open_channel.send_with_str(&json)?)) {
+                    console::error_1(&e.into());
+                }
+            }
+        }) as Box<dyn FnMut(JsValue)>);
+        let on_control_message = Closure::wrap(Box::new(move |event: JsValue| {
+            let data = get![event => "data"].as_string().unwrap_or_default();
+            match ControlMessage::from_json(&data) {
+                Ok(message) => on_message(message),
+                Err(e) => console::error_1(&e.into())
+            }
+        }) as Box<dyn FnMut(JsValue)>);
+        channel.set_onopen(on_channel_open.as_ref().dyn_ref());
+        channel.set_onmessage(on_control_message.as_ref().dyn_ref());
+        ControlChannel {
+            channel,
+            _callbacks: vec![on_channel_open, on_control_message],
+        }
+    }
+
+    pub fn send(&self, message: &ControlMessage) -> Result<(), Error> {
+        if self.channel.ready_state() != RtcDataChannelState::Open {
+            return Ok(());
+        }
+        Ok(self.channel.send_with_str(&message.to_json()?)?)
+    }
+
+    pub fn close(&self) {
+        self.channel.close();
+    }
+}
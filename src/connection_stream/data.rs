@@ -0,0 +1,110 @@
+use wasm_bindgen::prelude::*;
+use web_sys::*;
+use wasm_bindgen::JsCast;
+use std::rc::Rc;
+use crate::get;
+use crate::connection_stream::events::Events;
+use crate::error::Error;
+
+// Next to the control channel, pre-negotiated as well
+const DATA_CHANNEL_ID: u16 = 1;
+
+// Reliable and ordered by default, setting maxRetransmits or maxPacketLifeTime
+// makes the channel unreliable
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "camelCase", default)]
+pub struct DataChannelOptions {
+    pub ordered: bool,
+    pub max_retransmits: Option<u16>,
+    pub max_packet_life_time: Option<u16>,
+}
+
+impl Default for DataChannelOptions {
+    fn default() -> DataChannelOptions {
+        DataChannelOptions {
+            ordered: true,
+            max_retransmits: None,
+            max_packet_life_time: None,
+        }
+    }
+}
+
+impl DataChannelOptions {
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.max_retransmits.is_some() && self.max_packet_life_time.is_some() {
+            return Err(Error::InvalidOptions(
+                "dataChannel takes either maxRetransmits or maxPacketLifeTime".to_string()
+            ));
+        }
+        Ok(())
+    }
+
+    fn to_init(self) -> RtcDataChannelInit {
+        let init = RtcDataChannelInit::new();
+        init.set_negotiated(true);
+        init.set_id(DATA_CHANNEL_ID);
+        init.set_ordered(self.ordered);
+        if let Some(max_retransmits) = self.max_retransmits {
+            init.set_max_retransmits(max_retransmits);
+        }
+        if let Some(max_packet_life_time) = self.max_packet_life_time {
+            init.set_max_packet_life_time(max_packet_life_time);
+        }
+        init
+    }
+}
+
+// Application messages of a peer, received ones are emitted as "message" events
+pub struct DataChannel {
+    id: String,
+    channel: RtcDataChannel,
+    _callbacks: Vec<Closure<dyn FnMut(JsValue)>>,
+}
+
+impl DataChannel {
+    fn event_cb(id: String, events: Rc<Events>, name: &'static str) -> Closure<dyn FnMut(JsValue)> {
+        Closure::wrap(Box::new(move |_event: JsValue| {
+            events.emit(name, &id, &JsValue::UNDEFINED);
+        }) as Box<dyn FnMut(JsValue)>)
+    }
+
+    pub fn new(id: String, peer: &RtcPeerConnection, options: DataChannelOptions, events: Rc<Events>) -> DataChannel {
+        let channel = peer.create_data_channel_with_data_channel_dict("data", &options.to_init());
+        channel.set_binary_type(RtcDataChannelType::Arraybuffer);
+        let on_open = DataChannel::event_cb(id.clone(), events.clone(), "datachannelopen");
+        let on_close = DataChannel::event_cb(id.clone(), events.clone(), "datachannelclose");
+        let message_id = id.clone();
+        let on_message = Closure::wrap(Box::new(move |event: JsValue| {
+            events.emit("message", &message_id, &get![event => "data"]);
+        }) as Box<dyn FnMut(JsValue)>);
+        channel.set_onopen(on_open.as_ref().dyn_ref());
+        channel.set_onclose(on_close.as_ref().dyn_ref());
+        channel.set_onmessage(on_message.as_ref().dyn_ref());
+        DataChannel {
+            id,
+            channel,
+            _callbacks: vec![on_open, on_close, on_message],
+        }
+    }
+
+    // Strings are sent as text, ArrayBuffers and typed arrays as binary
+    pub fn send(&self, data: &JsValue) -> Result<(), Error> {
+        if self.channel.ready_state() != RtcDataChannelState::Open {
+            return Err(Error::ChannelClosed(self.id.clone()));
+        }
+        if let Some(text) = data.as_string() {
+            return Ok(self.channel.send_with_str(&text)?);
+        }
+        if let Some(buffer) = data.dyn_ref::<js_sys::ArrayBuffer>() {
+            return Ok(self.channel.send_with_array_buffer(buffer)?);
+        }
+        if js_sys::ArrayBuffer::is_view(data) {
+            return Ok(self.channel.send_with_array_buffer_view(data.unchecked_ref())?);
+        }
+        Err(Error::UnsupportedData)
+    }
+
+    pub fn close(&self) {
+        self.channel.close();
+    }
+}
@@ -0,0 +1,37 @@
+use wasm_bindgen::prelude::*;
+use web_sys::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+// JS listeners registered on Streaming, called as `listener(peer_id, detail)`
+#[derive(Default)]
+pub struct Events {
+    listeners: RefCell<HashMap<String, Vec<js_sys::Function>>>,
+}
+
+impl Events {
+    pub fn add(&self, name: String, listener: js_sys::Function) {
+        self.listeners.borrow_mut().entry(name).or_default().push(listener);
+    }
+
+    pub fn remove(&self, name: &str, listener: &js_sys::Function) {
+        if let Some(listeners) = self.listeners.borrow_mut().get_mut(name) {
+            let target: &JsValue = listener.as_ref();
+            listeners.retain(|l| AsRef::<JsValue>::as_ref(l) != target);
+        }
+    }
+
+    pub fn emit(&self, name: &str, id: &str, detail: &JsValue) {
+        // Listeners may add or remove listeners, don't hold the borrow
+        let listeners = match self.listeners.borrow().get(name) {
+            Some(listeners) => listeners.clone(),
+            None => return
+        };
+        let id = JsValue::from_str(id);
+        for listener in listeners {
+            if let Err(e) = listener.call2(&JsValue::NULL, &id, detail) {
+                console::error_1(&e);
+            }
+        }
+    }
+}
@@ -0,0 +1,313 @@
+use wasm_bindgen::prelude::*;
+use web_sys::*;
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::future_to_promise;
+use crate::{js_await, get, set};
+use crate::error::Error;
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum IceUrls {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct IceServer {
+    urls: IceUrls,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    credential: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    credential_type: Option<String>,
+}
+
+impl IceServer {
+    pub fn stun(url: &str) -> IceServer {
+        IceServer {
+            urls: IceUrls::One(url.to_string()),
+            username: None,
+            credential: None,
+            credential_type: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum CandidatePolicy {
+    All,
+    Udp,
+    Relay,
+}
+
+pub enum CandidateFilter {
+    All,
+    UdpOnly,
+    RelayOnly,
+    Custom(js_sys::Function),
+}
+
+impl From<CandidatePolicy> for CandidateFilter {
+    fn from(policy: CandidatePolicy) -> CandidateFilter {
+        match policy {
+            CandidatePolicy::All => CandidateFilter::All,
+            CandidatePolicy::Udp => CandidateFilter::UdpOnly,
+            CandidatePolicy::Relay => CandidateFilter::RelayOnly,
+        }
+    }
+}
+
+impl CandidateFilter {
+    pub fn from_js(value: JsValue) -> Result<CandidateFilter, Error> {
+        match value.dyn_into::<js_sys::Function>() {
+            Ok(predicate) => Ok(CandidateFilter::Custom(predicate)),
+            Err(value) => Ok(serde_wasm_bindgen::from_value::<CandidatePolicy>(value)?.into())
+        }
+    }
+
+    pub fn accepts(&self, candidate: &RtcIceCandidate) -> bool {
+        match self {
+            CandidateFilter::All => true,
+            CandidateFilter::UdpOnly => get![candidate => "protocol"].as_string().as_deref() == Some("udp"),
+            CandidateFilter::RelayOnly => get![candidate => "type"].as_string().as_deref() == Some("relay"),
+            CandidateFilter::Custom(predicate) => match predicate.call1(&JsValue::NULL, candidate) {
+                Ok(accepted) => accepted.is_truthy(),
+                Err(e) => {
+                    console::error_2(&"Candidate filter threw".into(), &e);
+                    false
+                }
+            }
+        }
+    }
+}
+
+fn create_config(servers: &[IceServer], filter: &CandidateFilter) -> Result<RtcConfiguration, JsValue> {
+    let config = RtcConfiguration::new();
+    config.set_ice_servers(&serde_wasm_bindgen::to_value(servers)?);
+    if let CandidateFilter::RelayOnly = filter {
+        config.set_ice_transport_policy(RtcIceTransportPolicy::Relay);
+    }
+    Ok(config)
+}
+
+const REFRESH_MARGIN_SECS: f64 = 60.;
+const MIN_REFRESH_SECS: f64 = 5.;
+
+// coturn's REST API encodes the credential expiry as "<unix timestamp>:<user>".
+fn username_expiry(username: &str) -> Option<f64> {
+    let (expiry, _user) = username.split_once(':')?;
+    expiry.parse::<f64>().ok()
+}
+
+fn refresh_delay_secs(expiry: f64, now: f64) -> f64 {
+    let remaining = expiry - now;
+    if remaining > 2. * REFRESH_MARGIN_SECS {
+        remaining - REFRESH_MARGIN_SECS
+    } else if remaining > 0. {
+        (remaining / 2.).max(MIN_REFRESH_SECS)
+    } else {
+        // The provider handed out expired credentials, asking again right away won't help
+        REFRESH_MARGIN_SECS
+    }
+}
+
+pub struct IceConfig {
+    servers: RefCell<Vec<IceServer>>,
+    provider: RefCell<Option<js_sys::Function>>,
+    filter: RefCell<CandidateFilter>,
+    generation: Cell<u32>,
+    // Refresh in flight, descriptions wait for it to gather with the latest credentials
+    refreshing: RefCell<Option<js_sys::Promise>>,
+}
+
+impl IceConfig {
+    pub fn new(servers: Vec<IceServer>, filter: CandidateFilter) -> IceConfig {
+        IceConfig {
+            servers: RefCell::new(servers),
+            provider: RefCell::new(None),
+            filter: RefCell::new(filter),
+            generation: Cell::new(0),
+            refreshing: RefCell::new(None),
+        }
+    }
+
+    pub fn create_config(&self) -> Result<RtcConfiguration, JsValue> {
+        create_config(&self.servers.borrow(), &self.filter.borrow())
+    }
+
+    pub fn set_filter(&self, filter: CandidateFilter) {
+        *self.filter.borrow_mut() = filter;
+    }
+
+    pub fn accepts(&self, candidate: &RtcIceCandidate) -> bool {
+        self.filter.borrow().accepts(candidate)
+    }
+
+    pub fn set_provider(&self, provider: js_sys::Function) {
+        *self.provider.borrow_mut() = Some(provider);
+    }
+
+    pub fn has_provider(&self) -> bool {
+        self.provider.borrow().is_some()
+    }
+
+    pub fn expiry(&self) -> Option<f64> {
+        self.servers.borrow().iter()
+            .filter_map(|server| server.username.as_deref().and_then(username_expiry))
+            .fold(None, |min: Option<f64>, expiry| Some(min.map_or(expiry, |m| m.min(expiry))))
+    }
+
+    pub fn is_expired(&self) -> bool {
+        match self.expiry() {
+            Some(expiry) => expiry <= js_sys::Date::now() / 1000.,
+            None => false
+        }
+    }
+
+    pub fn refresh_delay_ms(&self) -> Option<i32> {
+        let now = js_sys::Date::now() / 1000.;
+        self.expiry().map(|expiry| (refresh_delay_secs(expiry, now) * 1000.) as i32)
+    }
+
+    pub fn next_generation(&self) -> u32 {
+        let generation = self.generation.get().wrapping_add(1);
+        self.generation.set(generation);
+        generation
+    }
+
+    pub fn generation(&self) -> u32 {
+        self.generation.get()
+    }
+
+    pub fn set_refreshing(&self, promise: js_sys::Promise) {
+        *self.refreshing.borrow_mut() = Some(promise);
+    }
+
+    // A failed refresh is reported by its own promise, the current servers are used then
+    pub async fn ready(&self) {
+        let refreshing = self.refreshing.borrow().clone();
+        if let Some(promise) = refreshing {
+            let _ = js_await![promise];
+        }
+    }
+
+    pub async fn fetch(&self) -> Result<(), Error> {
+        let provider = match &*self.provider.borrow() {
+            Some(provider) => provider.clone(),
+            None => return Ok(())
+        };
+        let result = js_sys::Promise::resolve(&provider.call0(&JsValue::NULL)?);
+        let js_servers = js_await![result]?;
+        *self.servers.borrow_mut() = serde_wasm_bindgen::from_value(js_servers)?;
+        Ok(())
+    }
+}
+
+async fn add_candidate(peer: &RtcPeerConnection, candidate: RtcIceCandidate) -> JsValue {
+    let report = js_sys::Object::new();
+    let promise = peer.add_ice_candidate_with_opt_rtc_ice_candidate(Some(&candidate));
+    match js_await![promise] {
+        Ok(_) => {
+            set![report => "added", true];
+        }
+        Err(e) => {
+            set![report => "added", false];
+            set![report => "error", e];
+        }
+    }
+    set![report => "candidate", candidate];
+    report.into()
+}
+
+struct PendingCandidate {
+    candidate: RtcIceCandidate,
+    resolve: js_sys::Function,
+    reject: js_sys::Function,
+}
+
+// Remote candidates that arrive before the remote description are held back
+// until it has been applied, since addIceCandidate would reject them.
+#[derive(Default)]
+pub struct CandidateQueue {
+    pending: RefCell<Vec<PendingCandidate>>,
+}
+
+impl CandidateQueue {
+    pub fn add(queue: Rc<CandidateQueue>, peer: Rc<RtcPeerConnection>, candidate: RtcIceCandidate) -> js_sys::Promise {
+        if peer.remote_description().is_some() {
+            return future_to_promise(async move {
+                Ok(add_candidate(&peer, candidate).await)
+            });
+        }
+        js_sys::Promise::new(&mut |resolve, reject| {
+            queue.pending.borrow_mut().push(PendingCandidate { candidate: candidate.clone(), resolve, reject });
+        })
+    }
+
+    // Applies the remote description, then the candidates that waited for it.
+    // They are rejected along with the description
+    pub async fn set_remote(&self, peer: &RtcPeerConnection, description: &RtcSessionDescriptionInit) -> Result<(), Error> {
+        if let Err(e) = js_await![peer.set_remote_description(description)] {
+            self.reject_all(Error::SdpRejected(e.clone()));
+            return Err(Error::SdpRejected(e));
+        }
+        self.flush(peer).await;
+        Ok(())
+    }
+
+    async fn flush(&self, peer: &RtcPeerConnection) {
+        let pending: Vec<_> = self.pending.borrow_mut().drain(..).collect();
+        for PendingCandidate { candidate, resolve, .. } in pending {
+            let report = add_candidate(peer, candidate).await;
+            let _ = resolve.call1(&JsValue::NULL, &report);
+        }
+    }
+
+    pub fn reject_all(&self, error: Error) {
+        let reason: JsValue = error.into();
+        let pending: Vec<_> = self.pending.borrow_mut().drain(..).collect();
+        for PendingCandidate { reject, .. } in pending {
+            let _ = reject.call1(&JsValue::NULL, &reason);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn username_expiry_reads_the_timestamp() {
+        assert_eq!(username_expiry("1700000000:alice"), Some(1700000000.));
+        assert_eq!(username_expiry("1700000000:"), Some(1700000000.));
+    }
+
+    #[test]
+    fn username_expiry_ignores_other_usernames() {
+        assert_eq!(username_expiry("alice"), None);
+        assert_eq!(username_expiry("alice:1700000000"), None);
+        assert_eq!(username_expiry(""), None);
+    }
+
+    #[test]
+    fn refresh_delay_keeps_a_margin() {
+        assert_eq!(refresh_delay_secs(1000. + 3600., 1000.), 3600. - REFRESH_MARGIN_SECS);
+    }
+
+    #[test]
+    fn refresh_delay_halves_short_lifetimes() {
+        assert_eq!(refresh_delay_secs(1000. + 100., 1000.), 50.);
+        assert_eq!(refresh_delay_secs(1000. + 4., 1000.), MIN_REFRESH_SECS);
+    }
+
+    #[test]
+    fn refresh_delay_backs_off_on_expired_credentials() {
+        assert_eq!(refresh_delay_secs(1000., 1000.), REFRESH_MARGIN_SECS);
+        assert_eq!(refresh_delay_secs(500., 1000.), REFRESH_MARGIN_SECS);
+    }
+}
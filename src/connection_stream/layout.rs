@@ -0,0 +1,266 @@
+use crate::connection_stream::render_video::TileId;
+use crate::error::Error;
+
+const GAP: f64 = 10.;
+// Webcams mostly send 16:9
+const TILE_ASPECT: f64 = 16. / 9.;
+// Share of the canvas given to the tile in focus
+const FOCUS_SHARE: f64 = 0.8;
+const PIP_SHARE: f64 = 0.2;
+
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct Rect {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+impl Rect {
+    pub fn new(x: f64, y: f64, width: f64, height: f64) -> Rect {
+        Rect { x, y, width, height }
+    }
+
+    fn inset(self, by: f64) -> Rect {
+        Rect::new(self.x + by, self.y + by, (self.width - 2. * by).max(0.), (self.height - 2. * by).max(0.))
+    }
+
+    // The largest rectangle of the given aspect ratio, centered in this one
+    fn fit(self, aspect: f64) -> Rect {
+        let width = self.width.min(self.height * aspect);
+        let height = width / aspect;
+        Rect::new(self.x + (self.width - width) / 2., self.y + (self.height - height) / 2., width, height)
+    }
+}
+
+// Places the tiles on the canvas, the renderer puts the tiles of the peer in focus first
+pub trait Layout {
+    // One rectangle per tile, in the order of `tiles`
+    fn arrange(&self, tiles: &[TileId], width: f64, height: f64) -> Vec<Rect>;
+}
+
+// Picks the number of columns that gives the largest tiles
+fn grid(count: usize, area: Rect) -> Vec<Rect> {
+    if count == 0 {
+        return vec![];
+    }
+    let cell_size = |columns: usize| {
+        let rows = count.div_ceil(columns);
+        let width = (area.width - GAP * (columns - 1) as f64) / columns as f64;
+        let height = (area.height - GAP * (rows - 1) as f64) / rows as f64;
+        (width.max(0.), height.max(0.))
+    };
+    let tile_width = |columns: usize| {
+        let (width, height) = cell_size(columns);
+        width.min(height * TILE_ASPECT)
+    };
+    let columns = (1..=count).max_by(|a, b| tile_width(*a).total_cmp(&tile_width(*b))).unwrap_or(1);
+    let rows = count.div_ceil(columns);
+    let (cell_width, cell_height) = cell_size(columns);
+    (0..count)
+        .map(|i| {
+            let (row, column) = (i / columns, i % columns);
+            // The last row is centered when it isn't full
+            let in_row = if row == rows - 1 { count - row * columns } else { columns };
+            let offset = (columns - in_row) as f64 * (cell_width + GAP) / 2.;
+            let cell = Rect::new(
+                area.x + offset + column as f64 * (cell_width + GAP),
+                area.y + row as f64 * (cell_height + GAP),
+                cell_width,
+                cell_height,
+            );
+            cell.fit(TILE_ASPECT)
+        })
+        .collect()
+}
+
+// A single row or column of tiles, centered in the area
+fn strip(count: usize, area: Rect, horizontal: bool) -> Vec<Rect> {
+    let length = if horizontal { area.width } else { area.height };
+    let step = ((length - GAP * count.saturating_sub(1) as f64) / count.max(1) as f64).max(0.);
+    (0..count)
+        .map(|i| {
+            let start = i as f64 * (step + GAP);
+            let cell = match horizontal {
+                true => Rect::new(area.x + start, area.y, step, area.height),
+                false => Rect::new(area.x, area.y + start, area.width, step)
+            };
+            cell.fit(TILE_ASPECT)
+        })
+        .collect()
+}
+
+pub struct Grid;
+
+impl Layout for Grid {
+    fn arrange(&self, tiles: &[TileId], width: f64, height: f64) -> Vec<Rect> {
+        grid(tiles.len(), Rect::new(0., 0., width, height).inset(GAP))
+    }
+}
+
+// The tile in focus over the whole width, the others in a row below it
+pub struct ActiveSpeaker;
+
+impl Layout for ActiveSpeaker {
+    fn arrange(&self, tiles: &[TileId], width: f64, height: f64) -> Vec<Rect> {
+        let area = Rect::new(0., 0., width, height).inset(GAP);
+        if tiles.len() <= 1 {
+            return grid(tiles.len(), area);
+        }
+        let main_height = area.height * FOCUS_SHARE;
+        let main = Rect::new(area.x, area.y, area.width, main_height).fit(TILE_ASPECT);
+        let filmstrip = Rect::new(area.x, area.y + main_height + GAP, area.width, (area.height - main_height - GAP).max(0.));
+        std::iter::once(main).chain(strip(tiles.len() - 1, filmstrip, true)).collect()
+    }
+}
+
+// The tile in focus on the left, the others in a column on the right
+pub struct Sidebar;
+
+impl Layout for Sidebar {
+    fn arrange(&self, tiles: &[TileId], width: f64, height: f64) -> Vec<Rect> {
+        let area = Rect::new(0., 0., width, height).inset(GAP);
+        if tiles.len() <= 1 {
+            return grid(tiles.len(), area);
+        }
+        let main_width = area.width * FOCUS_SHARE;
+        let main = Rect::new(area.x, area.y, main_width, area.height).fit(TILE_ASPECT);
+        let filmstrip = Rect::new(area.x + main_width + GAP, area.y, (area.width - main_width - GAP).max(0.), area.height);
+        std::iter::once(main).chain(strip(tiles.len() - 1, filmstrip, false)).collect()
+    }
+}
+
+// The tile in focus fills the canvas, the others float over its bottom right corner,
+// stacked upwards then in more columns to the left. The ones that don't fit are hidden.
+pub struct PictureInPicture;
+
+impl Layout for PictureInPicture {
+    fn arrange(&self, tiles: &[TileId], width: f64, height: f64) -> Vec<Rect> {
+        let area = Rect::new(0., 0., width, height);
+        let inner = area.inset(GAP);
+        let pip_width = inner.width * PIP_SHARE;
+        let pip_height = pip_width / TILE_ASPECT;
+        let rows = ((inner.height + GAP) / (pip_height + GAP)).floor().max(1.) as usize;
+        let columns = ((inner.width + GAP) / (pip_width + GAP)).floor().max(1.) as usize;
+        tiles.iter().enumerate()
+            .map(|(i, _)| match i {
+                0 => area.fit(TILE_ASPECT),
+                i if i > rows * columns => Rect::default(),
+                i => {
+                    let (column, row) = ((i - 1) / rows, (i - 1) % rows);
+                    Rect::new(
+                        inner.x + inner.width - pip_width - column as f64 * (pip_width + GAP),
+                        inner.y + inner.height - pip_height - row as f64 * (pip_height + GAP),
+                        pip_width,
+                        pip_height,
+                    )
+                }
+            })
+            .collect()
+    }
+}
+
+pub fn from_name(name: &str) -> Result<Box<dyn Layout>, Error> {
+    match name {
+        "grid" => Ok(Box::new(Grid)),
+        "speaker" => Ok(Box::new(ActiveSpeaker)),
+        "sidebar" => Ok(Box::new(Sidebar)),
+        "pip" => Ok(Box::new(PictureInPicture)),
+        _ => Err(Error::InvalidOptions(format!("Unknown layout {}, expected grid, speaker, sidebar or pip", name)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tiles(count: usize) -> Vec<TileId> {
+        (0..count).map(|i| (format!("peer{}", i), "camera".to_string())).collect()
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-6, "{} != {}", actual, expected);
+    }
+
+    fn center_x(rect: &Rect) -> f64 {
+        rect.x + rect.width / 2.
+    }
+
+    #[test]
+    fn grid_picks_the_columns_with_the_largest_tiles() {
+        let rects = Grid.arrange(&tiles(4), 1280., 720.);
+        assert_eq!(rects[0].y, rects[1].y);
+        assert!(rects[2].y > rects[0].y + rects[0].height);
+        assert_eq!(rects[2].y, rects[3].y);
+        // A wide canvas keeps them in a single row
+        let rects = Grid.arrange(&tiles(3), 1920., 400.);
+        assert!(rects.iter().all(|rect| rect.y == rects[0].y));
+        assert!(rects[0].x < rects[1].x && rects[1].x < rects[2].x);
+    }
+
+    #[test]
+    fn grid_centers_a_partial_last_row() {
+        let rects = Grid.arrange(&tiles(3), 1280., 720.);
+        assert_eq!(rects[0].y, rects[1].y);
+        assert!(rects[2].y > rects[0].y);
+        assert_close(center_x(&rects[2]), 640.);
+        assert_close(center_x(&rects[0]) + center_x(&rects[1]), 1280.);
+    }
+
+    #[test]
+    fn speaker_puts_the_others_below_the_main_tile() {
+        let rects = ActiveSpeaker.arrange(&tiles(3), 1280., 720.);
+        let main = rects[0];
+        assert_close(main.y, GAP);
+        assert_close(main.height, 700. * FOCUS_SHARE);
+        assert_close(center_x(&main), 640.);
+        for rect in &rects[1..] {
+            assert_close(rect.y, main.y + main.height + GAP);
+            assert_close(rect.height, 700. - main.height - GAP);
+        }
+        assert!(rects[1].x + rects[1].width < rects[2].x);
+    }
+
+    #[test]
+    fn sidebar_puts_the_others_right_of_the_main_tile() {
+        let rects = Sidebar.arrange(&tiles(3), 1280., 720.);
+        let main = rects[0];
+        assert_close(main.x, GAP);
+        assert_close(main.width, 1260. * FOCUS_SHARE);
+        for rect in &rects[1..] {
+            assert_close(rect.x, main.x + main.width + GAP);
+            assert_close(rect.width, 1260. - main.width - GAP);
+        }
+        assert!(rects[1].y + rects[1].height < rects[2].y);
+    }
+
+    #[test]
+    fn pip_stacks_then_wraps_to_the_left() {
+        let rects = PictureInPicture.arrange(&tiles(18), 1280., 720.);
+        assert_eq!(rects[0], Rect::new(0., 0., 1280., 720.));
+        let (pip_width, pip_height) = (1260. * PIP_SHARE, 1260. * PIP_SHARE / TILE_ASPECT);
+        assert_eq!(rects[1], Rect::new(1270. - pip_width, 710. - pip_height, pip_width, pip_height));
+        // Four fit in a column at 720p, the fifth starts the next one
+        assert_close(rects[4].y, 710. - pip_height - 3. * (pip_height + GAP));
+        assert_close(rects[5].x, rects[1].x - pip_width - GAP);
+        assert_close(rects[5].y, rects[1].y);
+        for rect in &rects[1..17] {
+            assert!(rect.x >= GAP && rect.y >= GAP, "{:?}", rect);
+        }
+        // Four columns fit, the 17th tile is hidden
+        assert_eq!(rects[17], Rect::default());
+    }
+
+    #[test]
+    fn canvas_smaller_than_the_gaps() {
+        let layouts: Vec<Box<dyn Layout>> = vec![Box::new(Grid), Box::new(ActiveSpeaker), Box::new(Sidebar), Box::new(PictureInPicture)];
+        for layout in &layouts {
+            let rects = layout.arrange(&tiles(5), 2. * GAP - 5., 2. * GAP - 5.);
+            assert_eq!(rects.len(), 5);
+            for rect in rects {
+                assert!(rect.width >= 0. && rect.height >= 0., "{:?}", rect);
+                assert!(rect.x.is_finite() && rect.y.is_finite(), "{:?}", rect);
+            }
+        }
+    }
+}
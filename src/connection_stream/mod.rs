@@ -1,3 +1,20 @@
 pub mod video_stream;
+mod bandwidth;
+mod capture;
+mod codecs;
 mod connection;
-mod render_video;
\ No newline at end of file
+mod control;
+mod data;
+mod events;
+mod ice;
+mod layout;
+mod negotiation;
+mod options;
+mod overlay;
+mod reconnect;
+mod render_video;
+mod screen;
+mod sha256;
+mod simulcast;
+mod stats;
+mod transfer;
@@ -0,0 +1,169 @@
+use wasm_bindgen::prelude::*;
+use web_sys::*;
+use wasm_bindgen::JsCast;
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use crate::{js_await, get};
+use crate::connection_stream::ice::{CandidateQueue, IceConfig};
+use crate::connection_stream::codecs::CodecOptions;
+use crate::error::Error;
+
+// Owns the offer/answer exchange of a Connection. In "perfect negotiation"
+// mode (`polite` is set) both sides may offer at any time: on glare the polite
+// peer rolls back its own offer, the impolite one ignores the remote's.
+pub struct Negotiator {
+    peer: Rc<RtcPeerConnection>,
+    polite: Option<bool>,
+    making_offer: Cell<bool>,
+    // Classic mode only, set on the side that sent the first offer
+    offerer: Cell<bool>,
+    // Classic mode only, an offer was dropped while another one was in flight
+    pending: Cell<bool>,
+    on_description: RefCell<js_sys::Function>,
+    candidates: Rc<CandidateQueue>,
+    ice: Rc<IceConfig>,
+    codecs: CodecOptions,
+}
+
+impl Negotiator {
+    pub fn new(peer: Rc<RtcPeerConnection>, polite: Option<bool>, candidates: Rc<CandidateQueue>,
+               ice: Rc<IceConfig>, codecs: CodecOptions) -> Negotiator {
+        Negotiator {
+            peer,
+            polite,
+            making_offer: Cell::new(false),
+            offerer: Cell::new(false),
+            pending: Cell::new(false),
+            on_description: RefCell::new(js_sys::Function::new_no_args("")),
+            candidates,
+            ice,
+            codecs,
+        }
+    }
+
+    // Every offer and answer goes through here to get the codec preferences
+    // and the ICE servers of a refresh still in flight
+    pub async fn create_offer(&self, options: &RtcOfferOptions) -> Result<RtcSessionDescriptionInit, Error> {
+        self.ice.ready().await;
+        let native = self.codecs.set_codec_preferences(&self.peer);
+        let promise = self.peer.create_offer_with_rtc_offer_options(options);
+        let offer: RtcSessionDescriptionInit = js_await![promise].map_err(Error::SdpRejected)?.unchecked_into();
+        Ok(self.munge(offer, !native))
+    }
+
+    pub async fn create_answer(&self) -> Result<RtcSessionDescriptionInit, Error> {
+        self.ice.ready().await;
+        let native = self.codecs.set_codec_preferences(&self.peer);
+        let answer: RtcSessionDescriptionInit = js_await![self.peer.create_answer()]
+            .map_err(Error::SdpRejected)?.unchecked_into();
+        Ok(self.munge(answer, !native))
+    }
+
+    // Opus settings only exist in the SDP, codecs are reordered there without setCodecPreferences
+    fn munge(&self, description: RtcSessionDescriptionInit, reorder: bool) -> RtcSessionDescriptionInit {
+        if self.codecs.is_empty() {
+            return description;
+        }
+        let sdp = description.get_sdp().unwrap_or_default();
+        let munged = RtcSessionDescriptionInit::new(description.get_type());
+        munged.set_sdp(&self.codecs.munge(&sdp, reorder));
+        munged
+    }
+
+    pub fn is_perfect(&self) -> bool {
+        self.polite.is_some()
+    }
+
+    pub fn set_offerer(&self) {
+        self.offerer.set(true);
+    }
+
+    // Perfect negotiation gets negotiationneeded again instead
+    pub fn take_pending(&self) -> bool {
+        self.pending.replace(false)
+    }
+
+    pub fn set_on_description(&self, closure: js_sys::Function) {
+        *self.on_description.borrow_mut() = closure;
+    }
+
+    fn emit(&self, description: &RtcSessionDescriptionInit) {
+        if let Err(e) = self.on_description.borrow().call1(&JsValue::NULL, description) {
+            console::error_1(&e);
+        }
+    }
+
+    pub async fn negotiation_needed(&self) -> Result<(), Error> {
+        self.making_offer.set(true);
+        let result = self.send_offer(false).await;
+        self.making_offer.set(false);
+        result
+    }
+
+    pub async fn restart_ice(&self) -> Result<(), Error> {
+        if self.is_perfect() {
+            // Triggers negotiationneeded with an ICE restart offer
+            let restart_ice: js_sys::Function = get![self.peer => "restartIce"].unchecked_into();
+            restart_ice.call0(&self.peer)?;
+            return Ok(());
+        }
+        // Both sides usually fail together and the classic API can't resolve two
+        // crossing offers, so the answering side waits for the restart offer
+        if !self.offerer.get() {
+            return Ok(());
+        }
+        self.making_offer.set(true);
+        let result = self.send_offer(true).await;
+        self.making_offer.set(false);
+        result
+    }
+
+    // Sends a new offer for an already negotiated connection, e.g. after its
+    // tracks changed. The first offer is left to create_offer/negotiationneeded,
+    // one already sent gets followed by a new one once answered.
+    pub async fn renegotiate(&self) -> Result<(), Error> {
+        if self.peer.remote_description().is_none() {
+            if self.peer.local_description().is_some() && !self.is_perfect() {
+                self.pending.set(true);
+            }
+            return Ok(());
+        }
+        self.negotiation_needed().await
+    }
+
+    async fn send_offer(&self, ice_restart: bool) -> Result<(), Error> {
+        let options = RtcOfferOptions::new();
+        options.set_ice_restart(ice_restart);
+        let offer = self.create_offer(&options).await?;
+        // A remote offer may have been applied while ours was being created
+        if self.peer.signaling_state() != RtcSignalingState::Stable {
+            if !self.is_perfect() {
+                self.pending.set(true);
+            }
+            return Ok(());
+        }
+        js_await![self.peer.set_local_description(&offer)].map_err(Error::SdpRejected)?;
+        self.emit(&offer);
+        Ok(())
+    }
+
+    pub async fn handle_description(&self, description: RtcSessionDescriptionInit) -> Result<(), Error> {
+        let is_offer = description.get_type() == RtcSdpType::Offer;
+        let collision = is_offer
+            && (self.making_offer.get() || self.peer.signaling_state() != RtcSignalingState::Stable);
+        if collision && self.polite != Some(true) {
+            return Ok(());
+        }
+        if collision {
+            let rollback = RtcSessionDescriptionInit::new(RtcSdpType::Rollback);
+            js_await![self.peer.set_local_description(&rollback)].map_err(Error::SdpRejected)?;
+        }
+        self.candidates.set_remote(&self.peer, &description).await?;
+        if is_offer {
+            let answer = self.create_answer().await?;
+            js_await![self.peer.set_local_description(&answer)].map_err(Error::SdpRejected)?;
+            self.emit(&answer);
+        }
+        Ok(())
+    }
+}
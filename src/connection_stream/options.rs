@@ -0,0 +1,57 @@
+use wasm_bindgen::prelude::*;
+use crate::connection_stream::ice::{CandidatePolicy, IceServer};
+use crate::connection_stream::reconnect::ReconnectPolicy;
+use crate::connection_stream::data::DataChannelOptions;
+use crate::connection_stream::codecs::CodecOptions;
+use crate::connection_stream::simulcast::SimulcastOptions;
+use crate::error::Error;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct StreamingOptions {
+    pub ice_servers: Vec<IceServer>,
+    pub candidate_filter: CandidatePolicy,
+    pub perfect_negotiation: bool,
+    pub local_id: Option<String>,
+    pub reconnect: ReconnectPolicy,
+    pub data_channel: DataChannelOptions,
+    pub codecs: CodecOptions,
+    pub simulcast: SimulcastOptions,
+}
+
+impl Default for StreamingOptions {
+    fn default() -> StreamingOptions {
+        StreamingOptions {
+            ice_servers: vec![IceServer::stun("stun:stun.l.google.com:19302")],
+            candidate_filter: CandidatePolicy::All,
+            perfect_negotiation: false,
+            local_id: None,
+            reconnect: ReconnectPolicy::default(),
+            data_channel: DataChannelOptions::default(),
+            codecs: CodecOptions::default(),
+            simulcast: SimulcastOptions::default(),
+        }
+    }
+}
+
+impl StreamingOptions {
+    pub fn from_js(value: JsValue) -> Result<StreamingOptions, Error> {
+        if value.is_undefined() || value.is_null() {
+            return Ok(StreamingOptions::default());
+        }
+        let options: StreamingOptions = serde_wasm_bindgen::from_value(value)?;
+        if options.perfect_negotiation && options.local_id.is_none() {
+            return Err(Error::InvalidOptions("perfectNegotiation requires a localId".to_string()));
+        }
+        options.data_channel.validate()?;
+        Ok(options)
+    }
+
+    // The peer with the lower id is the polite one
+    pub fn polite(&self, peer_id: &str) -> Option<bool> {
+        match (&self.local_id, self.perfect_negotiation) {
+            (Some(local_id), true) => Some(local_id.as_str() < peer_id),
+            _ => None
+        }
+    }
+}
@@ -0,0 +1,205 @@
+use wasm_bindgen::prelude::*;
+use web_sys::*;
+use std::f64::consts::TAU;
+use crate::connection_stream::control::MediaState;
+use crate::connection_stream::stats::Quality;
+use crate::error::Error;
+
+// How a video fills a tile whose aspect ratio differs from its own
+#[derive(Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum FitMode {
+    // Whole video, the rest of the tile shows the background
+    #[default]
+    Contain,
+    // Whole tile, the video is cropped around its center
+    Cover,
+    Stretch,
+}
+
+// How the renderer draws the tiles, each overlay can be turned off
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct RendererOptions {
+    pub names: bool,
+    pub signal_bars: bool,
+    pub mute_badge: bool,
+    pub fit: FitMode,
+    // Any CSS colour, behind letterboxed videos and tiles without a frame yet
+    pub background: String,
+    pub corner_radius: f64,
+}
+
+impl Default for RendererOptions {
+    fn default() -> RendererOptions {
+        RendererOptions {
+            names: true,
+            signal_bars: true,
+            mute_badge: true,
+            fit: FitMode::default(),
+            background: "#202124".to_string(),
+            corner_radius: 0.,
+        }
+    }
+}
+
+impl RendererOptions {
+    pub fn from_js(value: JsValue) -> Result<RendererOptions, Error> {
+        if value.is_undefined() || value.is_null() {
+            return Ok(RendererOptions::default());
+        }
+        Ok(serde_wasm_bindgen::from_value(value)?)
+    }
+}
+
+// What the renderer knows of a participant, shared by all of its tiles
+#[derive(Default)]
+pub struct PeerInfo {
+    pub media: MediaState,
+    pub name: Option<String>,
+    pub quality: Option<Quality>,
+}
+
+pub struct Tile<'a> {
+    pub id: &'a str,
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+    // Camera-off and mute state only apply to the camera tile of a peer
+    pub camera: bool,
+}
+
+impl Tile<'_> {
+    fn unit(&self) -> f64 {
+        (self.width.min(self.height) / 14.).max(8.)
+    }
+}
+
+// Everything drawn until the next restore() stays inside the tile
+pub fn clip_tile(context: &CanvasRenderingContext2d, tile: &Tile, radius: f64) {
+    context.begin_path();
+    // roundRect is recent, older browsers get square corners
+    if radius <= 0. || context.round_rect_with_f64(tile.x, tile.y, tile.width, tile.height, radius).is_err() {
+        context.rect(tile.x, tile.y, tile.width, tile.height);
+    }
+    context.clip();
+}
+
+pub fn draw_video(context: &CanvasRenderingContext2d, video: &HtmlVideoElement, tile: &Tile, options: &RendererOptions) {
+    context.set_fill_style_str(&options.background);
+    context.fill_rect(tile.x, tile.y, tile.width, tile.height);
+    let (video_width, video_height) = (video.video_width() as f64, video.video_height() as f64);
+    // No frame yet
+    if video_width == 0. || video_height == 0. {
+        return;
+    }
+    let _ = match options.fit {
+        FitMode::Stretch => context.draw_image_with_html_video_element_and_dw_and_dh(
+            video, tile.x, tile.y, tile.width, tile.height),
+        FitMode::Contain => {
+            let scale = (tile.width / video_width).min(tile.height / video_height);
+            let (width, height) = (video_width * scale, video_height * scale);
+            context.draw_image_with_html_video_element_and_dw_and_dh(
+                video, tile.x + (tile.width - width) / 2., tile.y + (tile.height - height) / 2., width, height)
+        }
+        FitMode::Cover => {
+            let scale = (tile.width / video_width).max(tile.height / video_height);
+            let (source_width, source_height) = (tile.width / scale, tile.height / scale);
+            context.draw_image_with_html_video_element_and_sw_and_sh_and_dx_and_dy_and_dw_and_dh(
+                video, (video_width - source_width) / 2., (video_height - source_height) / 2., source_width, source_height,
+                tile.x, tile.y, tile.width, tile.height)
+        }
+    };
+}
+
+// Up to two letters taken from the words of a name, e.g. "jane-doe" => "JD"
+fn initials(name: &str) -> String {
+    name.split(|c: char| c.is_whitespace() || c == '-' || c == '_' || c == '.')
+        .filter_map(|word| word.chars().next())
+        .take(2)
+        .flat_map(char::to_uppercase)
+        .collect()
+}
+
+// Drawn instead of the last frame while the camera is off
+pub fn draw_placeholder(context: &CanvasRenderingContext2d, tile: &Tile, peer: &PeerInfo) {
+    let (x, y, width, height) = (tile.x, tile.y, tile.width, tile.height);
+    context.set_fill_style_str("#3c4043");
+    context.fill_rect(x, y, width, height);
+    context.set_fill_style_str("#5f6368");
+    context.begin_path();
+    let _ = context.arc(x + width / 2., y + height / 2., width.min(height) / 4., 0., TAU);
+    context.fill();
+    context.set_fill_style_str("#ffffff");
+    context.set_font(&format!("{}px sans-serif", (width.min(height) / 6.).round()));
+    context.set_text_align("center");
+    context.set_text_baseline("middle");
+    let name = peer.name.as_deref().unwrap_or(tile.id);
+    let _ = context.fill_text(&initials(name), x + width / 2., y + height / 2.);
+}
+
+fn draw_mute_badge(context: &CanvasRenderingContext2d, tile: &Tile) {
+    let radius = tile.unit();
+    let (center_x, center_y) = (tile.x + tile.width - radius * 1.5, tile.y + tile.height - radius * 1.5);
+    context.set_fill_style_str("#d93025");
+    context.begin_path();
+    let _ = context.arc(center_x, center_y, radius, 0., TAU);
+    context.fill();
+    context.set_stroke_style_str("#ffffff");
+    context.set_line_width(radius / 4.);
+    context.begin_path();
+    context.move_to(center_x - radius / 2., center_y - radius / 2.);
+    context.line_to(center_x + radius / 2., center_y + radius / 2.);
+    context.stroke();
+}
+
+fn draw_name(context: &CanvasRenderingContext2d, tile: &Tile, name: &str) {
+    let unit = tile.unit();
+    context.set_font(&format!("{}px sans-serif", unit.round()));
+    let width = context.measure_text(name).map(|metrics| metrics.width()).unwrap_or(0.);
+    context.set_fill_style_str("rgba(0, 0, 0, 0.5)");
+    context.fill_rect(tile.x + unit / 2., tile.y + tile.height - unit * 2.5, width + unit, unit * 2.);
+    context.set_fill_style_str("#ffffff");
+    context.set_text_align("left");
+    context.set_text_baseline("middle");
+    let _ = context.fill_text(name, tile.x + unit, tile.y + tile.height - unit * 1.5);
+}
+
+// Three bars in the top right corner, lit according to the quality
+fn draw_signal_bars(context: &CanvasRenderingContext2d, tile: &Tile, quality: Quality) {
+    let (lit, color) = match quality {
+        Quality::Good => (3, "#34a853"),
+        Quality::Fair => (2, "#fbbc04"),
+        Quality::Poor => (1, "#d93025"),
+    };
+    let unit = tile.unit();
+    let bar_width = unit / 2.;
+    let right = tile.x + tile.width - unit;
+    let bottom = tile.y + unit * 2.5;
+    for bar in 0..3 {
+        let height = unit * 0.6 * (bar + 1) as f64;
+        let x = right - (3 - bar) as f64 * bar_width * 1.5;
+        context.set_fill_style_str(if bar < lit { color } else { "rgba(255, 255, 255, 0.4)" });
+        context.fill_rect(x, bottom - height, bar_width, height);
+    }
+}
+
+pub fn draw_overlays(context: &CanvasRenderingContext2d, tile: &Tile, peer: &PeerInfo, options: &RendererOptions) {
+    if options.names {
+        if let Some(name) = &peer.name {
+            draw_name(context, tile, name);
+        }
+    }
+    if !tile.camera {
+        return;
+    }
+    if options.signal_bars {
+        if let Some(quality) = peer.quality {
+            draw_signal_bars(context, tile, quality);
+        }
+    }
+    if options.mute_badge && !peer.media.audio {
+        draw_mute_badge(context, tile);
+    }
+}
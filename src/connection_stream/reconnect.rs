@@ -0,0 +1,155 @@
+use web_sys::*;
+use wasm_bindgen_futures::spawn_local;
+use std::cell::Cell;
+use std::rc::Rc;
+use crate::js_extend::set_timeout;
+use crate::connection_stream::events::Events;
+use crate::connection_stream::ice::IceConfig;
+use crate::connection_stream::negotiation::Negotiator;
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ReconnectPolicy {
+    pub grace_period_ms: i32,
+    pub max_attempts: u32,
+    pub attempt_timeout_ms: i32,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> ReconnectPolicy {
+        ReconnectPolicy {
+            grace_period_ms: 3000,
+            max_attempts: 3,
+            attempt_timeout_ms: 10_000,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ReconnectEvent<'a> {
+    state: &'a str,
+    attempt: u32,
+}
+
+// Follows the ICE connection state of a peer: a disconnect is given a grace
+// period, then ICE is restarted until it recovers or the policy gives up.
+pub struct Reconnector {
+    id: String,
+    peer: Rc<RtcPeerConnection>,
+    negotiator: Rc<Negotiator>,
+    ice: Rc<IceConfig>,
+    events: Rc<Events>,
+    policy: ReconnectPolicy,
+    attempts: Cell<u32>,
+    reconnecting: Cell<bool>,
+    // Bumped on every transition so stale timers do nothing
+    generation: Cell<u32>,
+    on_give_up: Box<dyn Fn()>,
+}
+
+impl Reconnector {
+    pub fn new(id: String, peer: Rc<RtcPeerConnection>, negotiator: Rc<Negotiator>, ice: Rc<IceConfig>,
+               events: Rc<Events>, policy: ReconnectPolicy, on_give_up: Box<dyn Fn()>) -> Reconnector {
+        Reconnector {
+            id,
+            peer,
+            negotiator,
+            ice,
+            events,
+            policy,
+            attempts: Cell::new(0),
+            reconnecting: Cell::new(false),
+            generation: Cell::new(0),
+            on_give_up,
+        }
+    }
+
+    fn emit(&self, state: &str) {
+        let event = ReconnectEvent { state, attempt: self.attempts.get() };
+        match serde_wasm_bindgen::to_value(&event) {
+            Ok(detail) => self.events.emit("reconnect", &self.id, &detail),
+            Err(e) => console::error_1(&e.into())
+        }
+    }
+
+    fn next_generation(&self) -> u32 {
+        let generation = self.generation.get().wrapping_add(1);
+        self.generation.set(generation);
+        generation
+    }
+
+    fn is_down(&self) -> bool {
+        matches!(self.peer.ice_connection_state(),
+            RtcIceConnectionState::Disconnected | RtcIceConnectionState::Failed)
+    }
+
+    fn schedule_restart(self: &Rc<Self>, delay: i32) {
+        let generation = self.next_generation();
+        let reconnector = self.clone();
+        let scheduled = set_timeout(move || {
+            if reconnector.generation.get() == generation && reconnector.is_down() {
+                reconnector.restart();
+            }
+        }, delay);
+        if let Err(e) = scheduled {
+            console::error_1(&e);
+        }
+    }
+
+    fn give_up(&self) {
+        self.next_generation();
+        self.emit("failed");
+        (self.on_give_up)();
+    }
+
+    fn restart(self: &Rc<Self>) {
+        let attempt = self.attempts.get() + 1;
+        if attempt > self.policy.max_attempts {
+            self.give_up();
+            return;
+        }
+        self.attempts.set(attempt);
+        self.emit("restarting");
+        // Pick up credentials refreshed since the connection was created
+        if let Err(e) = self.ice.create_config().and_then(|config| {
+            self.peer.set_configuration_with_configuration(&config)
+        }) {
+            console::error_1(&e);
+        }
+        let negotiator = self.negotiator.clone();
+        spawn_local(async move {
+            if let Err(e) = negotiator.restart_ice().await {
+                console::error_1(&e.into());
+            }
+        });
+        self.schedule_restart(self.policy.attempt_timeout_ms);
+    }
+
+    pub fn on_state_change(self: &Rc<Self>) {
+        match self.peer.ice_connection_state() {
+            RtcIceConnectionState::Connected | RtcIceConnectionState::Completed => {
+                self.next_generation();
+                if self.reconnecting.replace(false) {
+                    self.emit("recovered");
+                    self.attempts.set(0);
+                }
+            }
+            // While restarting, the attempt timeout is already pending
+            RtcIceConnectionState::Disconnected if self.attempts.get() == 0 => {
+                self.reconnecting.set(true);
+                self.emit("disconnected");
+                self.schedule_restart(self.policy.grace_period_ms);
+            }
+            RtcIceConnectionState::Failed => {
+                self.reconnecting.set(true);
+                self.next_generation();
+                self.restart();
+            }
+            RtcIceConnectionState::Closed => {
+                self.next_generation();
+                (self.on_give_up)();
+            }
+            _ => {}
+        }
+    }
+}
@@ -4,13 +4,18 @@ use wasm_bindgen::prelude::{JsValue, Closure};
 use wasm_bindgen::JsCast;
 use std::cell::RefCell;
 use wasm_bindgen::__rt::std::collections::HashMap;
+use crate::error::Error;
+use crate::connection_stream::control::MediaState;
+use crate::connection_stream::overlay::{clip_tile, draw_overlays, draw_placeholder, draw_video, PeerInfo, RendererOptions, Tile};
+use crate::connection_stream::stats::Quality;
+use crate::connection_stream::layout::{Grid, Layout, Rect};
 
 
 pub fn create_video(muted: bool) -> Result<Rc<HtmlVideoElement>, JsValue> {
     let document = web_sys::window().unwrap().document().unwrap();
-    let video = document.create_element("video").unwrap().unchecked_into::<HtmlVideoElement>();
+    let video = document.create_element("video")?.unchecked_into::<HtmlVideoElement>();
     video.set_autoplay(true);
-    video.set_attribute("playsinline", "true").unwrap();
+    video.set_attribute("playsinline", "true")?;
     video.set_muted(muted);
     video.style().set_property("display", "none")?;
     Ok(Rc::new(video))
@@ -23,65 +28,106 @@ fn request_animation_frame(f: &Closure<dyn FnMut()>) {
 
 type DrawCb = Rc<RefCell<Option<Closure<dyn FnMut()>>>>;
 
+// Called with a peer id and the size of its camera tile
+pub type TileSizeCb = Box<dyn Fn(&str, f64, f64)>;
+
+// (peer id, stream id), a peer gets a tile per stream it sends, e.g. camera and screen
+pub type TileId = (String, String);
+
+// Stable, the other tiles keep their order
+fn focus_first(order: &[TileId], focus: Option<&str>) -> Vec<TileId> {
+    let mut tiles = order.to_vec();
+    if let Some(focus) = focus {
+        tiles.sort_by_key(|(peer, _)| peer != focus);
+    }
+    tiles
+}
+
 struct VideoPos {
     video_rc: Rc<HtmlVideoElement>,
-    x: f64,
-    y: f64,
+    rect: Rect,
+    // The peer's camera-off placeholder and mute badge are drawn on this tile
+    camera: bool,
 }
 
 pub struct VideoRenderer {
+    canvas: Rc<HtmlCanvasElement>,
     context_rc: Rc<web_sys::CanvasRenderingContext2d>,
-    videos: Rc<RefCell<HashMap<String, VideoPos>>>,
-    next_pos: (f64, f64),
+    videos: Rc<RefCell<HashMap<TileId, VideoPos>>>,
+    peers: Rc<RefCell<HashMap<String, PeerInfo>>>,
+    options: Rc<RefCell<RendererOptions>>,
+    layout: Box<dyn Layout>,
+    // Tiles in the order they were added, the layout gets them in this order
+    order: Vec<TileId>,
+    // Peer whose tiles are laid out first
+    focus: Option<String>,
+    // `order` with the focus first, tiles are drawn in this order so the ones
+    // floating over the focused tile stay visible
+    draw_order: Rc<RefCell<Vec<TileId>>>,
     dims: Rc<RefCell<(f64, f64)>>,
     managed: bool,
+    // Size of the canvas in CSS pixels, the backing store is `pixel_ratio` times larger
     width_height: Rc<RefCell<(f64, f64)>>,
-    _on_resize: Closure<dyn FnMut()>,
+    pixel_ratio: f64,
+    // Called for each peer after a layout
+    on_tile_size: Option<TileSizeCb>,
 }
 
 impl VideoRenderer {
-    #[inline]
-    fn on_resize(canvas_rc: Rc<HtmlCanvasElement>, width_height: Rc<RefCell<(f64, f64)>>) -> Closure<dyn FnMut()> {
-        let mut wh = *width_height.borrow_mut();
-        let cv2 = canvas_rc.clone();
-        let closure = Closure::wrap(Box::new(move || {
-            let new_width = canvas_rc.offset_width();
-            let new_height = canvas_rc.offset_height();
-            wh.0 = new_width as f64;
-            wh.1 = new_height as f64;
-        }) as Box<dyn FnMut()>);
-        cv2.set_onresize(closure.as_ref().dyn_ref());
-        closure
-    }
-
-    pub fn new(canvas_rc: Rc<HtmlCanvasElement>, width: i32, height: i32) -> Result<VideoRenderer, JsValue> {
+    pub fn new(canvas_rc: Rc<HtmlCanvasElement>, f_width: f64, f_height: f64) -> Result<VideoRenderer, JsValue> {
         let context = canvas_rc
             .get_context("2d")?
             .unwrap()
             .dyn_into::<web_sys::CanvasRenderingContext2d>()?;
-        let f_width = width as f64;
-        let f_height = height as f64;
-        let width_height = Rc::new(RefCell::new((f_width.clone(), f_height.clone())));
-        let _on_resize = VideoRenderer::on_resize(canvas_rc, width_height.clone());
-        let renderer = VideoRenderer {
+        let mut renderer = VideoRenderer {
+            canvas: canvas_rc,
             context_rc: Rc::new(context),
             videos: Rc::new(RefCell::new(HashMap::new())),
-            next_pos: (10.0, 10.0),
+            peers: Rc::new(RefCell::new(HashMap::new())),
+            options: Rc::new(RefCell::new(RendererOptions::default())),
+            layout: Box::new(Grid),
+            order: vec![],
+            focus: None,
+            draw_order: Rc::new(RefCell::new(vec![])),
             dims: Rc::new(RefCell::new((f_width, f_height))),
             managed: true,
-            width_height,
-            _on_resize,
+            width_height: Rc::new(RefCell::new((f_width, f_height))),
+            pixel_ratio: 1.,
+            on_tile_size: None,
         };
-
+        renderer.resize(f_width, f_height);
         Ok(renderer)
     }
 
+    // Sizes the backing store for devicePixelRatio so HiDPI screens stay sharp,
+    // the tiles keep being placed in CSS pixels
+    pub fn resize(&mut self, width: f64, height: f64) {
+        let pixel_ratio = window().map(|window| window.device_pixel_ratio()).filter(|ratio| *ratio > 0.).unwrap_or(1.);
+        self.canvas.set_width((width * pixel_ratio).round() as u32);
+        self.canvas.set_height((height * pixel_ratio).round() as u32);
+        let style = self.canvas.style();
+        let _ = style.set_property("width", &format!("{}px", width));
+        let _ = style.set_property("height", &format!("{}px", height));
+        // Resizing the canvas resets its transform
+        let _ = self.context_rc.set_transform(pixel_ratio, 0., 0., pixel_ratio, 0., 0.);
+        self.pixel_ratio = pixel_ratio;
+        *self.width_height.borrow_mut() = (width, height);
+        self.relayout();
+    }
+
+    // devicePixelRatio changed, e.g. after a zoom or a move to another screen
+    pub fn rescale(&mut self) {
+        let (width, height) = *self.width_height.borrow();
+        self.resize(width, height);
+    }
+
     #[inline]
     pub fn clear_all(&self) {
         let (width, height) = *self.width_height.borrow();
         self.context_rc.clear_rect(0., 0., width, height);
     }
 
+    // Size of the tiles added from now on in the unmanaged mode, set_video_rect resizes the current ones
     #[inline]
     pub fn set_dims(&mut self, x: f64, y: f64) {
         self.dims.borrow_mut().0 = x;
@@ -89,107 +135,179 @@ impl VideoRenderer {
         // console::log_1(&format!("{}, {}", f_width, f_height).into());
     }
 
-    #[inline]
-    pub fn set_video_pos(&mut self, id: String, x: f64, y: f64) -> Result<JsValue, JsValue> {
-        match self.videos.borrow_mut().get_mut(&id) {
-            Some(video) => {
-                video.x = x;
-                video.y = y;
+    // Without a stream id, updates the camera tile of the peer, the area it leaves is cleared
+    fn update_rect<F: FnOnce(&mut Rect)>(&mut self, id: String, stream_id: Option<String>, update: F) -> Result<JsValue, Error> {
+        let mut videos = self.videos.borrow_mut();
+        let video = videos.iter_mut()
+            .find(|((peer, stream), video)| *peer == id && match &stream_id {
+                Some(stream_id) => stream == stream_id,
+                None => video.camera
+            });
+        match video {
+            Some((_, video)) => {
+                let old = video.rect;
+                self.context_rc.clear_rect(old.x, old.y, old.width, old.height);
+                update(&mut video.rect);
                 Ok(JsValue::TRUE)
             }
-            None => Err(JsValue::FALSE)
+            None => Err(Error::UnknownPeer(id))
         }
     }
 
+    #[inline]
+    pub fn set_video_pos(&mut self, id: String, stream_id: Option<String>, x: f64, y: f64) -> Result<JsValue, Error> {
+        self.update_rect(id, stream_id, |rect| {
+            rect.x = x;
+            rect.y = y;
+        })
+    }
+
+    // The next relayout would undo it in the managed mode
+    pub fn set_video_rect(&mut self, id: String, stream_id: Option<String>, rect: Rect) -> Result<JsValue, Error> {
+        if self.managed {
+            return Err(Error::ManagedLayout);
+        }
+        self.update_rect(id, stream_id, |current| *current = rect)
+    }
+
+    // Kept per peer, the state may arrive before the peer's first stream
+    pub fn set_media_state(&mut self, id: &str, media: MediaState) {
+        self.peers.borrow_mut().entry(id.to_string()).or_default().media = media;
+    }
+
+    pub fn set_name(&mut self, id: &str, name: Option<String>) {
+        self.peers.borrow_mut().entry(id.to_string()).or_default().name = name;
+    }
+
+    pub fn set_quality(&mut self, id: &str, quality: Option<Quality>) {
+        self.peers.borrow_mut().entry(id.to_string()).or_default().quality = quality;
+    }
+
+    pub fn set_options(&mut self, options: RendererOptions) {
+        *self.options.borrow_mut() = options;
+    }
+
+    pub fn has_video(&self, id: &TileId) -> bool {
+        self.videos.borrow().contains_key(id)
+    }
+
+    pub fn has_camera(&self, peer: &str) -> bool {
+        self.videos.borrow().iter().any(|((id, _), video)| id == peer && video.camera)
+    }
+
+    pub fn set_on_tile_size(&mut self, on_tile_size: TileSizeCb) {
+        self.on_tile_size = Some(on_tile_size);
+    }
+
+    pub fn set_layout(&mut self, layout: Box<dyn Layout>) {
+        self.layout = layout;
+        self.relayout();
+    }
+
+    pub fn set_focus(&mut self, focus: Option<String>) {
+        self.focus = focus;
+        self.relayout();
+    }
+
     #[inline]
     pub fn not_managed(&mut self) {
         self.managed = false;
     }
 
 
-    fn update_count(&mut self, count: f64) {
-        let width_height = *self.width_height.borrow();
-        let possible_width = (width_height.0 - 10. * count) / count;
-        if possible_width > 210. {
-            self.set_dims(possible_width, possible_width);
-            let mut current_x: f64 = 0.;
-            let mut current_y: f64 = 0.;
-            for (_, video_pos) in &mut *self.videos.as_ref().borrow_mut() {
-                video_pos.x = current_x;
-                current_x += possible_width + 10.;
-                if current_x + self.dims.borrow().0 >= width_height.0 {
-                    current_x = 0.;
-                    current_y += width_height.1 + 10.;
-                    video_pos.y = possible_width + 10.;
+    // Places every tile with the layout, the tiles of the peer in focus first
+    fn relayout(&mut self) {
+        let tiles = focus_first(&self.order, self.focus.as_deref());
+        *self.draw_order.borrow_mut() = tiles.clone();
+        if !self.managed {
+            return;
+        }
+        let (width, height) = *self.width_height.borrow();
+        let rects = self.layout.arrange(&tiles, width, height);
+        {
+            let mut videos = self.videos.borrow_mut();
+            for (tile, rect) in tiles.iter().zip(rects) {
+                if let Some(video) = videos.get_mut(tile) {
+                    video.rect = rect;
                 }
             }
-            self.next_pos = (current_x, current_y);
-            self.clear_all();
         }
-    }
-
-    fn video_pos_managed(&mut self, video_rc: Rc<HtmlVideoElement>) -> VideoPos {
-        let count = self.videos.as_ref().borrow().len() as f64 + 1.;
-        self.update_count(count);
-        let dims = *self.dims.borrow();
-        let (x, y) = self.next_pos;
-        let (width, _height) = *self.width_height.borrow();
-        if x + dims.0 < width {
-            self.next_pos.0 += dims.0 + 1.0;
-        } else {
-            self.next_pos.0 = 10.0;
-            self.next_pos.1 += dims.1 + 10.0;
-        }
-        VideoPos {
-            video_rc,
-            x,
-            y,
+        self.clear_all();
+        if let Some(on_tile_size) = &self.on_tile_size {
+            // In device pixels, what the video needs to look sharp
+            for ((peer, _), video) in self.videos.borrow().iter().filter(|(_, video)| video.camera) {
+                on_tile_size(peer, video.rect.width * self.pixel_ratio, video.rect.height * self.pixel_ratio);
+            }
         }
     }
 
-    fn video_pos_not_managed(&self, video_rc: Rc<HtmlVideoElement>) -> VideoPos {
-        VideoPos {
+    #[inline]
+    pub fn add_video(&mut self, id: TileId, video_rc: Rc<HtmlVideoElement>, camera: bool) {
+        // Off the canvas until set_video_pos places it in the unmanaged mode
+        let (width, height) = *self.dims.borrow();
+        let pos = VideoPos {
             video_rc,
-            x: -40000f64,
-            y: -40000f64,
-        }
+            rect: Rect::new(-40000., -40000., width, height),
+            camera,
+        };
+        self.videos.borrow_mut().insert(id.clone(), pos);
+        self.order.push(id);
+        self.relayout();
     }
 
-    #[inline]
-    pub fn add_video(&mut self, id: String, video_rc: Rc<HtmlVideoElement>) {
-        let pos = match self.managed {
-            true => self.video_pos_managed(video_rc),
-            false => self.video_pos_not_managed(video_rc)
-        };
-        self.videos.borrow_mut().insert(id, pos);
+    pub fn remove_peer(&mut self, peer: &str) {
+        let tiles: Vec<TileId> = self.videos.borrow().keys()
+            .filter(|(id, _)| id == peer)
+            .cloned()
+            .collect();
+        for tile in tiles {
+            self.remove_video(&tile);
+        }
+        self.peers.borrow_mut().remove(peer);
     }
 
-    pub fn remove_video(&mut self, id: &String) {
-        let count: f64 = {
-            let videos_rc = self.videos.clone();
-            let mut videos = videos_rc.borrow_mut();
-            let vid = videos.remove(id).unwrap();
-            let (width, height) = *self.dims.borrow();
-            self.context_rc.clear_rect(vid.x, vid.y, width, height);
-            videos.len() as f64
-        };
-        if !self.managed {
-            return;
+    pub fn remove_video(&mut self, id: &TileId) {
+        if let Some(video) = self.videos.borrow_mut().remove(id) {
+            let rect = video.rect;
+            self.context_rc.clear_rect(rect.x, rect.y, rect.width, rect.height);
         }
-        self.update_count(count);
+        self.order.retain(|tile| tile != id);
+        self.relayout();
     }
 
     pub fn start(&self) -> Result<DrawCb, JsValue> {
         let func = Rc::new(RefCell::new(None));
         let func_cp = func.clone();
         let videos = self.videos.clone();
+        let draw_order = self.draw_order.clone();
+        let peers_rc = self.peers.clone();
+        let options = self.options.clone();
         let context = self.context_rc.clone();
-        let dims_rc = self.dims.clone();
         *func_cp.borrow_mut() = Some(Closure::wrap(Box::new(move || {
-            let dims = dims_rc.borrow();
-            for (_, video_pos) in videos.borrow().iter() {
-                context.draw_image_with_html_video_element_and_dw_and_dh(
-                    video_pos.video_rc.as_ref(), video_pos.x, video_pos.y, dims.0, dims.1).unwrap();
+            let peers = peers_rc.borrow();
+            let options = options.borrow();
+            let unknown = PeerInfo::default();
+            let videos = videos.borrow();
+            let tiles = draw_order.borrow();
+            for (id, video_pos) in tiles.iter().filter_map(|tile| Some((&tile.0, videos.get(tile)?))) {
+                let peer = peers.get(id).unwrap_or(&unknown);
+                let tile = Tile {
+                    id,
+                    x: video_pos.rect.x,
+                    y: video_pos.rect.y,
+                    width: video_pos.rect.width,
+                    height: video_pos.rect.height,
+                    camera: video_pos.camera,
+                };
+                context.save();
+                clip_tile(&context, &tile, options.corner_radius);
+                if peer.media.video || !tile.camera {
+                    draw_video(&context, &video_pos.video_rc, &tile, &options);
+                } else {
+                    draw_placeholder(&context, &tile, peer);
+                }
+                draw_overlays(&context, &tile, peer, &options);
+                context.restore();
             }
             request_animation_frame(func.borrow().as_ref().unwrap());
         }) as Box<dyn FnMut()>));
@@ -209,3 +327,33 @@ impl VideoRenderer {
         Err(_e) => None
     }
 }*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection_stream::layout::PictureInPicture;
+
+    fn tile(peer: &str, stream: &str) -> TileId {
+        (peer.to_string(), stream.to_string())
+    }
+
+    #[test]
+    fn focused_tiles_come_first() {
+        let order = vec![tile("a", "camera"), tile("b", "camera"), tile("c", "camera"), tile("b", "screen")];
+        let tiles = focus_first(&order, Some("b"));
+        assert_eq!(tiles, vec![tile("b", "camera"), tile("b", "screen"), tile("a", "camera"), tile("c", "camera")]);
+        assert_eq!(focus_first(&order, None), order);
+    }
+
+    #[test]
+    fn pip_tiles_are_drawn_after_the_focused_one() {
+        let order = vec![tile("a", "camera"), tile("b", "camera"), tile("c", "camera")];
+        let tiles = focus_first(&order, Some("c"));
+        let rects = PictureInPicture.arrange(&tiles, 1280., 720.);
+        assert_eq!(tiles[0], tile("c", "camera"));
+        assert_eq!(rects[0], Rect::new(0., 0., 1280., 720.));
+        for rect in &rects[1..] {
+            assert!(rect.width < rects[0].width && rect.height < rects[0].height, "{:?}", rect);
+        }
+    }
+}
@@ -0,0 +1,91 @@
+use wasm_bindgen::prelude::*;
+use web_sys::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::spawn_local;
+use std::rc::Rc;
+use crate::js_await;
+use crate::connection_stream::connection::Connection;
+use crate::connection_stream::render_video::create_video;
+
+fn log_rejection(promise: js_sys::Promise) {
+    spawn_local(async move {
+        if let Err(e) = js_await![promise] {
+            console::error_1(&e);
+        }
+    });
+}
+
+// A getDisplayMedia capture, sent to the peers next to the camera or in its place
+pub struct ScreenShare {
+    stream: MediaStream,
+    video: Rc<HtmlVideoElement>,
+    // Local stream whose camera the screen replaces, set in replace mode. The
+    // camera is looked up when needed since it may be switched during the share
+    camera: Option<MediaStream>,
+    _on_ended: Closure<dyn FnMut(JsValue)>,
+}
+
+impl ScreenShare {
+    pub fn new(stream: MediaStream, camera: Option<MediaStream>,
+               on_ended: Closure<dyn FnMut(JsValue)>) -> Result<ScreenShare, JsValue> {
+        let video = create_video(true)?;
+        video.set_src_object(Some(&stream));
+        let _ = video.play();
+        // The browser's "stop sharing" button ends the video track
+        for track in stream.get_video_tracks().iter() {
+            track.unchecked_into::<MediaStreamTrack>().set_onended(on_ended.as_ref().dyn_ref());
+        }
+        Ok(ScreenShare { stream, video, camera, _on_ended: on_ended })
+    }
+
+    pub fn replaces_camera(&self) -> bool {
+        self.camera.is_some()
+    }
+
+    pub fn video(&self) -> Rc<HtmlVideoElement> {
+        self.video.clone()
+    }
+
+    fn tracks(&self) -> Vec<MediaStreamTrack> {
+        self.stream.get_tracks().iter().map(|track| track.unchecked_into::<MediaStreamTrack>()).collect()
+    }
+
+    fn replaced_camera(&self, track: &MediaStreamTrack) -> Option<MediaStreamTrack> {
+        let camera = self.camera.as_ref().filter(|_| track.kind() == "video")?;
+        camera.get_video_tracks().get(0).dyn_into().ok()
+    }
+
+    pub fn publish(&self, connection: &Connection) {
+        let mut added = false;
+        for track in self.tracks() {
+            match self.replaced_camera(&track).and_then(|camera| connection.replace_track(&camera.id(), &track)) {
+                Some(promise) => log_rejection(promise),
+                None => added |= connection.add_track(&track, &self.stream)
+            }
+        }
+        if added {
+            connection.renegotiate();
+        }
+    }
+
+    pub fn unpublish(&self, connection: &Connection) {
+        let mut removed = false;
+        for track in self.tracks() {
+            match self.replaced_camera(&track).and_then(|camera| connection.replace_track(&track.id(), &camera)) {
+                Some(promise) => log_rejection(promise),
+                None => removed |= connection.remove_track(&track.id())
+            }
+        }
+        if removed {
+            connection.renegotiate();
+        }
+    }
+
+    pub fn stop(&self) {
+        for track in self.tracks() {
+            track.set_onended(None);
+            track.stop();
+        }
+        self.video.set_src_object(None);
+    }
+}
@@ -0,0 +1,109 @@
+use wasm_bindgen::prelude::*;
+use web_sys::*;
+
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SimulcastLayer {
+    pub rid: String,
+    pub scale_resolution_down_by: f64,
+    pub max_bitrate_kbps: Option<u32>,
+}
+
+impl SimulcastLayer {
+    fn new(rid: &str, scale_resolution_down_by: f64, max_bitrate_kbps: u32) -> SimulcastLayer {
+        SimulcastLayer {
+            rid: rid.to_string(),
+            scale_resolution_down_by,
+            max_bitrate_kbps: Some(max_bitrate_kbps),
+        }
+    }
+}
+
+// Video is sent as several encodings of the camera, each peer then asks for
+// the layers its tiles need, see ControlMessage::Layers and ControlMessage::TileSize
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct SimulcastOptions {
+    pub enabled: bool,
+    // From the lowest to the highest resolution
+    pub layers: Vec<SimulcastLayer>,
+}
+
+impl Default for SimulcastOptions {
+    fn default() -> SimulcastOptions {
+        SimulcastOptions {
+            enabled: false,
+            layers: vec![
+                SimulcastLayer::new("q", 4., 150),
+                SimulcastLayer::new("h", 2., 500),
+                SimulcastLayer::new("f", 1., 1500),
+            ],
+        }
+    }
+}
+
+// A layer and whether the peer receives it
+#[derive(Clone)]
+pub struct LayerState {
+    pub layer: SimulcastLayer,
+    pub active: bool,
+}
+
+pub struct Simulcast {
+    layers: Vec<SimulcastLayer>,
+}
+
+impl Simulcast {
+    pub fn new(options: &SimulcastOptions) -> Simulcast {
+        Simulcast {
+            layers: if options.enabled { options.layers.clone() } else { vec![] },
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.layers.is_empty()
+    }
+
+    pub fn transceiver_init(&self, stream: &MediaStream) -> RtcRtpTransceiverInit {
+        let encodings: js_sys::Array = self.layers.iter()
+            .map(|layer| {
+                let encoding = RtcRtpEncodingParameters::new();
+                encoding.set_rid(&layer.rid);
+                encoding.set_scale_resolution_down_by(layer.scale_resolution_down_by as f32);
+                if let Some(kbps) = layer.max_bitrate_kbps {
+                    encoding.set_max_bitrate(kbps * 1000);
+                }
+                JsValue::from(encoding)
+            })
+            .collect();
+        let init = RtcRtpTransceiverInit::new();
+        init.set_direction(RtcRtpTransceiverDirection::Sendrecv);
+        init.set_streams(&js_sys::Array::of1(stream));
+        init.set_send_encodings(&encodings);
+        init
+    }
+
+    // The smallest layer that still covers the tile, the largest one when none does
+    pub fn layer_for_tile(&self, track: &MediaStreamTrack, width: f64, height: f64) -> Option<String> {
+        let settings = track.get_settings();
+        let (track_width, track_height) = (settings.get_width()? as f64, settings.get_height()? as f64);
+        let by_scale = |a: &&SimulcastLayer, b: &&SimulcastLayer|
+            a.scale_resolution_down_by.total_cmp(&b.scale_resolution_down_by);
+        let layer = self.layers.iter()
+            .filter(|layer| track_width / layer.scale_resolution_down_by >= width
+                && track_height / layer.scale_resolution_down_by >= height)
+            .max_by(by_scale)
+            .or_else(|| self.layers.iter().min_by(by_scale));
+        layer.map(|layer| layer.rid.clone())
+    }
+
+    // Every layer is sent until the peer asks for some
+    pub fn states(&self, requested: Option<&Vec<String>>) -> Vec<LayerState> {
+        self.layers.iter()
+            .map(|layer| LayerState {
+                layer: layer.clone(),
+                active: requested.map(|rids| rids.contains(&layer.rid)).unwrap_or(true),
+            })
+            .collect()
+    }
+}
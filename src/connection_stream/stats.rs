@@ -0,0 +1,205 @@
+use wasm_bindgen::prelude::*;
+use web_sys::*;
+use wasm_bindgen::JsCast;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use crate::{js_await, set};
+use crate::connection_stream::events::Events;
+use crate::error::Error;
+
+#[derive(Serialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Quality {
+    #[default]
+    Good,
+    Fair,
+    Poor,
+}
+
+impl Quality {
+    // Thresholds past which calls are noticeably degraded, then hard to follow
+    fn from_stats(stats: &ConnectionStats) -> Quality {
+        let loss = stats.packet_loss.unwrap_or(0.);
+        let rtt = stats.rtt_ms.unwrap_or(0.);
+        let jitter = stats.jitter_ms.unwrap_or(0.);
+        if loss > 0.05 || rtt > 400. || jitter > 50. {
+            Quality::Poor
+        } else if loss > 0.02 || rtt > 200. || jitter > 30. {
+            Quality::Fair
+        } else {
+            Quality::Good
+        }
+    }
+}
+
+// The fields of the RTCStats dictionaries that are read, whatever their type
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+struct RawStats {
+    #[serde(rename = "type")]
+    stats_type: String,
+    id: String,
+    kind: Option<String>,
+    timestamp: Option<f64>,
+    bytes_received: Option<f64>,
+    bytes_sent: Option<f64>,
+    packets_received: Option<f64>,
+    packets_lost: Option<f64>,
+    jitter: Option<f64>,
+    frames_decoded: Option<f64>,
+    frames_dropped: Option<f64>,
+    frame_width: Option<u32>,
+    frame_height: Option<u32>,
+    current_round_trip_time: Option<f64>,
+    state: Option<String>,
+    nominated: Option<bool>,
+    local_candidate_id: Option<String>,
+    selected_candidate_pair_id: Option<String>,
+    candidate_type: Option<String>,
+}
+
+// Counters carried over to the next sample to turn them into rates
+#[derive(Clone, Copy, Default)]
+struct Totals {
+    timestamp: f64,
+    bytes_received: f64,
+    bytes_sent: f64,
+    packets_received: f64,
+    packets_lost: f64,
+}
+
+#[derive(Serialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ConnectionStats {
+    pub rtt_ms: Option<f64>,
+    pub jitter_ms: Option<f64>,
+    // Fraction of the packets lost since the previous sample
+    pub packet_loss: Option<f64>,
+    pub bitrate_in: Option<f64>,
+    pub bitrate_out: Option<f64>,
+    pub frames_decoded: Option<f64>,
+    pub frames_dropped: Option<f64>,
+    pub frame_width: Option<u32>,
+    pub frame_height: Option<u32>,
+    // host, srflx, prflx or relay
+    pub candidate_type: Option<String>,
+    pub quality: Quality,
+}
+
+fn add(total: &mut Option<f64>, value: Option<f64>) {
+    if let Some(value) = value {
+        *total = Some(total.unwrap_or(0.) + value);
+    }
+}
+
+fn per_second(current: f64, previous: f64, seconds: f64) -> Option<f64> {
+    match seconds > 0. {
+        true => Some((current - previous).max(0.) / seconds),
+        false => None
+    }
+}
+
+// Turns a getStats report into ConnectionStats, rates are computed against `previous`
+fn parse_report(report: &js_sys::Map, previous: Option<Totals>) -> Result<(ConnectionStats, Totals), Error> {
+    let mut entries: Vec<RawStats> = vec![];
+    let mut error = None;
+    report.for_each(&mut |value, _key| match serde_wasm_bindgen::from_value(value) {
+        Ok(raw) => entries.push(raw),
+        Err(e) => error = Some(e)
+    });
+    if let Some(e) = error {
+        return Err(e.into());
+    }
+    let mut stats = ConnectionStats::default();
+    let mut totals = Totals::default();
+    let mut selected_pair = None;
+    for raw in entries.iter() {
+        totals.timestamp = totals.timestamp.max(raw.timestamp.unwrap_or(0.));
+        match raw.stats_type.as_str() {
+            "inbound-rtp" => {
+                totals.bytes_received += raw.bytes_received.unwrap_or(0.);
+                totals.packets_received += raw.packets_received.unwrap_or(0.);
+                totals.packets_lost += raw.packets_lost.unwrap_or(0.);
+                if let Some(jitter) = raw.jitter {
+                    stats.jitter_ms = Some(stats.jitter_ms.unwrap_or(0.).max(jitter * 1000.));
+                }
+                if raw.kind.as_deref() == Some("video") {
+                    add(&mut stats.frames_decoded, raw.frames_decoded);
+                    add(&mut stats.frames_dropped, raw.frames_dropped);
+                    stats.frame_width = stats.frame_width.max(raw.frame_width);
+                    stats.frame_height = stats.frame_height.max(raw.frame_height);
+                }
+            }
+            "outbound-rtp" => totals.bytes_sent += raw.bytes_sent.unwrap_or(0.),
+            "transport" if raw.selected_candidate_pair_id.is_some() => {
+                selected_pair = raw.selected_candidate_pair_id.clone();
+            }
+            _ => {}
+        }
+    }
+    // Firefox has no transport stats, the nominated pair is the selected one there
+    let by_id: HashMap<&str, &RawStats> = entries.iter().map(|raw| (raw.id.as_str(), raw)).collect();
+    let pair = match selected_pair {
+        Some(id) => by_id.get(id.as_str()).copied(),
+        None => entries.iter().find(|raw| raw.stats_type == "candidate-pair"
+            && raw.nominated == Some(true) && raw.state.as_deref() == Some("succeeded"))
+    };
+    if let Some(pair) = pair {
+        stats.rtt_ms = pair.current_round_trip_time.map(|rtt| rtt * 1000.);
+        stats.candidate_type = pair.local_candidate_id.as_deref()
+            .and_then(|id| by_id.get(id))
+            .and_then(|candidate| candidate.candidate_type.clone());
+    }
+    // No rates on the first sample, the counters start with the connection
+    let seconds = previous.map(|previous| (totals.timestamp - previous.timestamp) / 1000.).unwrap_or(0.);
+    let previous = previous.unwrap_or_default();
+    stats.bitrate_in = per_second(totals.bytes_received, previous.bytes_received, seconds).map(|bytes| bytes * 8.);
+    stats.bitrate_out = per_second(totals.bytes_sent, previous.bytes_sent, seconds).map(|bytes| bytes * 8.);
+    let lost = (totals.packets_lost - previous.packets_lost).max(0.);
+    let received = (totals.packets_received - previous.packets_received).max(0.);
+    if lost + received > 0. {
+        stats.packet_loss = Some(lost / (lost + received));
+    }
+    stats.quality = Quality::from_stats(&stats);
+    Ok((stats, totals))
+}
+
+// Samples the stats of a Connection, each sample is emitted as a "stats" event
+// and "qualitychange" follows when the score moves
+pub struct StatsSampler {
+    id: String,
+    peer: Rc<RtcPeerConnection>,
+    events: Rc<Events>,
+    previous: Cell<Option<Totals>>,
+    quality: Cell<Option<Quality>>,
+    on_quality: Box<dyn Fn(Quality)>,
+}
+
+impl StatsSampler {
+    pub fn new(id: String, peer: Rc<RtcPeerConnection>, events: Rc<Events>, on_quality: Box<dyn Fn(Quality)>) -> StatsSampler {
+        StatsSampler {
+            id,
+            peer,
+            events,
+            previous: Cell::new(None),
+            quality: Cell::new(None),
+            on_quality,
+        }
+    }
+
+    pub async fn sample(&self) -> Result<JsValue, Error> {
+        let report: js_sys::Map = js_await![self.peer.get_stats()]?.unchecked_into();
+        let (stats, totals) = parse_report(&report, self.previous.get())?;
+        self.previous.set(Some(totals));
+        let value = serde_wasm_bindgen::to_value(&stats)?;
+        self.events.emit("stats", &self.id, &value);
+        if self.quality.replace(Some(stats.quality)) != Some(stats.quality) {
+            (self.on_quality)(stats.quality);
+            let detail = js_sys::Object::new();
+            set![detail => "quality", serde_wasm_bindgen::to_value(&stats.quality)?];
+            self.events.emit("qualitychange", &self.id, &detail);
+        }
+        Ok(value)
+    }
+}
@@ -0,0 +1,406 @@
+use wasm_bindgen::prelude::*;
+use web_sys::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::{future_to_promise, spawn_local};
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::Rc;
+use crate::{js_await, get, set};
+use crate::connection_stream::events::Events;
+use crate::connection_stream::sha256::Sha256;
+use crate::error::Error;
+
+const LABEL_PREFIX: &str = "file:";
+const CHUNK_SIZE: u32 = 16 * 1024;
+// Sending pauses above MAX_BUFFERED and resumes once drained to LOW_BUFFERED
+const MAX_BUFFERED: u32 = 1024 * 1024;
+const LOW_BUFFERED: u32 = 256 * 1024;
+// The file is read a slice at a time, never whole
+const READ_SIZE: f64 = MAX_BUFFERED as f64;
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum TransferMessage {
+    #[serde(rename_all = "camelCase")]
+    Header { name: String, size: f64, mime_type: String },
+    // After the last chunk, the SHA-256 of the whole file
+    End { hash: String },
+    Cancel,
+    // The receiver's answer to End, the sender reports the transfer after it
+    Received,
+    Failed,
+}
+
+impl TransferMessage {
+    fn send(&self, channel: &RtcDataChannel) -> Result<(), Error> {
+        let value = serde_wasm_bindgen::to_value(self)?;
+        Ok(channel.send_with_str(&String::from(js_sys::JSON::stringify(&value)?))?)
+    }
+
+    fn parse(text: &str) -> Result<TransferMessage, Error> {
+        Ok(serde_wasm_bindgen::from_value(js_sys::JSON::parse(text)?)?)
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TransferEvent<'a> {
+    transfer_id: &'a str,
+    name: &'a str,
+    direction: &'a str,
+    transferred: f64,
+    size: f64,
+}
+
+fn transfer_id() -> String {
+    let random = (js_sys::Math::random() * u32::MAX as f64) as u32;
+    format!("{:x}-{:08x}", js_sys::Date::now() as u64, random)
+}
+
+// Resolves once `handler` fires, or the channel closes, see TransferState::closed
+async fn wait_for(channel: &RtcDataChannel, set_handler: fn(&RtcDataChannel, Option<&js_sys::Function>)) {
+    let promise = js_sys::Promise::new(&mut |resolve, _reject| set_handler(channel, Some(&resolve)));
+    let _ = js_await![promise];
+    set_handler(channel, None);
+}
+
+fn cancel_channel(channel: &RtcDataChannel) {
+    if channel.ready_state() == RtcDataChannelState::Open {
+        let _ = TransferMessage::Cancel.send(channel);
+    }
+    channel.close();
+}
+
+// One side of a transfer, each transfer has a data channel of its own
+struct TransferState {
+    id: String,
+    peer_id: String,
+    name: RefCell<String>,
+    size: Cell<f64>,
+    direction: &'static str,
+    channel: RtcDataChannel,
+    events: Rc<Events>,
+    done: Cell<bool>,
+    // Sender only, woken by the receiver's answer to End
+    on_reply: RefCell<Option<js_sys::Function>>,
+    verified: Cell<Option<bool>>,
+}
+
+impl TransferState {
+    fn emit(&self, name: &str, transferred: f64) {
+        let event = TransferEvent {
+            transfer_id: &self.id,
+            name: &self.name.borrow(),
+            direction: self.direction,
+            transferred,
+            size: self.size.get(),
+        };
+        match serde_wasm_bindgen::to_value(&event) {
+            Ok(detail) => self.events.emit(name, &self.peer_id, &detail),
+            Err(e) => console::error_1(&e.into())
+        }
+    }
+
+    fn fail(&self, error: JsValue) {
+        let detail = js_sys::Object::new();
+        set![detail => "transferId", self.id.clone()];
+        set![detail => "error", error];
+        self.events.emit("filefailed", &self.peer_id, &detail);
+    }
+
+    fn replied(&self, verified: bool) {
+        self.verified.set(Some(verified));
+        if let Some(resolve) = self.on_reply.borrow_mut().take() {
+            let _ = resolve.call0(&JsValue::NULL);
+        }
+    }
+
+    fn closed(&self) {
+        // Wake up a sender waiting for the channel to open or drain, or for the answer
+        let waiting = [self.channel.onopen(), self.channel.onbufferedamountlow(), self.on_reply.borrow_mut().take()];
+        for handler in waiting.iter().flatten() {
+            let _ = handler.call0(&JsValue::NULL);
+        }
+        if !self.done.replace(true) {
+            self.emit("filecancelled", 0.);
+        }
+    }
+
+    fn ensure_open(&self) -> Result<(), Error> {
+        match self.channel.ready_state() {
+            RtcDataChannelState::Open => Ok(()),
+            _ => Err(Error::TransferCancelled(self.id.clone()))
+        }
+    }
+
+    async fn send(&self, file: Blob) -> Result<(), Error> {
+        if self.channel.ready_state() == RtcDataChannelState::Connecting {
+            wait_for(&self.channel, RtcDataChannel::set_onopen).await;
+        }
+        self.ensure_open()?;
+        let header = TransferMessage::Header {
+            name: self.name.borrow().clone(),
+            size: self.size.get(),
+            mime_type: file.type_(),
+        };
+        header.send(&self.channel)?;
+        let size = file.size();
+        let mut hasher = Sha256::default();
+        let mut offset = 0.;
+        while offset < size {
+            let end = (offset + READ_SIZE).min(size);
+            let slice = file.slice_with_f64_and_f64(offset, end)?;
+            let buffer: js_sys::ArrayBuffer = js_await![slice.array_buffer()]?.unchecked_into();
+            hasher.update(&js_sys::Uint8Array::new(&buffer).to_vec());
+            let length = buffer.byte_length();
+            let mut sent = 0;
+            while sent < length {
+                if self.channel.buffered_amount() > MAX_BUFFERED {
+                    wait_for(&self.channel, RtcDataChannel::set_onbufferedamountlow).await;
+                }
+                self.ensure_open()?;
+                let chunk_length = CHUNK_SIZE.min(length - sent);
+                let chunk = js_sys::Uint8Array::new_with_byte_offset_and_length(&buffer, sent, chunk_length);
+                self.channel.send_with_array_buffer_view(&chunk)?;
+                sent += chunk_length;
+                self.emit("fileprogress", offset + sent as f64);
+            }
+            offset = end;
+        }
+        let reply = js_sys::Promise::new(&mut |resolve, _reject| *self.on_reply.borrow_mut() = Some(resolve));
+        TransferMessage::End { hash: hasher.finish() }.send(&self.channel)?;
+        let _ = js_await![reply];
+        match self.verified.get() {
+            Some(true) => {
+                self.done.set(true);
+                self.emit("filesent", size);
+                Ok(())
+            }
+            Some(false) => Err(Error::IntegrityCheck(self.id.clone())),
+            // Closed before answering
+            None => Err(Error::TransferCancelled(self.id.clone()))
+        }
+    }
+}
+
+// An incoming file, chunks are hashed as they come and kept until the end message
+struct IncomingFile {
+    state: TransferState,
+    mime_type: RefCell<String>,
+    hasher: RefCell<Sha256>,
+    parts: js_sys::Array,
+    received: Cell<f64>,
+}
+
+impl IncomingFile {
+    fn on_message(self: &Rc<Self>, data: JsValue) -> Result<(), Error> {
+        let text = match data.as_string() {
+            Some(text) => text,
+            None => {
+                let chunk: js_sys::ArrayBuffer = data.dyn_into()?;
+                self.received.set(self.received.get() + chunk.byte_length() as f64);
+                self.hasher.borrow_mut().update(&js_sys::Uint8Array::new(&chunk).to_vec());
+                self.parts.push(&chunk);
+                self.state.emit("fileprogress", self.received.get());
+                return Ok(());
+            }
+        };
+        match TransferMessage::parse(&text)? {
+            TransferMessage::Header { name, size, mime_type } => {
+                *self.state.name.borrow_mut() = name;
+                self.state.size.set(size);
+                *self.mime_type.borrow_mut() = mime_type;
+                self.state.emit("filestart", 0.);
+            }
+            TransferMessage::End { hash } => {
+                self.state.done.set(true);
+                let result = self.finish(&hash);
+                // Closing lets the answer go out first
+                let reply = if result.is_ok() { TransferMessage::Received } else { TransferMessage::Failed };
+                let _ = reply.send(&self.state.channel);
+                self.state.channel.close();
+                if let Err(e) = result {
+                    self.state.fail(e.into());
+                }
+            }
+            // Cancel, the answers only go the other way
+            _ => self.state.channel.close()
+        }
+        Ok(())
+    }
+
+    fn finish(&self, hash: &str) -> Result<(), Error> {
+        if std::mem::take(&mut *self.hasher.borrow_mut()).finish() != hash {
+            return Err(Error::IntegrityCheck(self.state.id.clone()));
+        }
+        let options = FilePropertyBag::new();
+        options.set_type(&self.mime_type.borrow());
+        let file = File::new_with_buffer_source_sequence_and_options(&self.parts, &self.state.name.borrow(), &options)?;
+        let detail = js_sys::Object::new();
+        set![detail => "transferId", self.state.id.clone()];
+        set![detail => "file", file];
+        self.state.events.emit("filereceived", &self.state.peer_id, &detail);
+        Ok(())
+    }
+}
+
+struct ActiveTransfer {
+    channel: RtcDataChannel,
+    _callbacks: Vec<Closure<dyn FnMut(JsValue)>>,
+}
+
+// The transfers of a Connection, in both directions
+pub struct FileTransfers {
+    peer_id: String,
+    events: Rc<Events>,
+    active: RefCell<HashMap<String, ActiveTransfer>>,
+}
+
+impl FileTransfers {
+    pub fn new(peer_id: String, events: Rc<Events>) -> FileTransfers {
+        FileTransfers { peer_id, events, active: RefCell::new(HashMap::new()) }
+    }
+
+    fn state(&self, id: String, channel: RtcDataChannel, direction: &'static str) -> TransferState {
+        channel.set_binary_type(RtcDataChannelType::Arraybuffer);
+        TransferState {
+            id,
+            peer_id: self.peer_id.clone(),
+            name: RefCell::new(String::new()),
+            size: Cell::new(0.),
+            direction,
+            channel,
+            events: self.events.clone(),
+            done: Cell::new(false),
+            on_reply: RefCell::new(None),
+            verified: Cell::new(None),
+        }
+    }
+
+    // Runs on the channel's own close event, so the callbacks are dropped afterwards
+    fn close_cb(self: &Rc<Self>, id: String, on_close: impl Fn() + 'static) -> Closure<dyn FnMut(JsValue)> {
+        let transfers = self.clone();
+        Closure::wrap(Box::new(move |_event: JsValue| {
+            on_close();
+            let transfers = transfers.clone();
+            let id = id.clone();
+            spawn_local(async move {
+                transfers.active.borrow_mut().remove(&id);
+            });
+        }) as Box<dyn FnMut(JsValue)>)
+    }
+
+    pub fn send(self: &Rc<Self>, peer: &RtcPeerConnection, file: Blob, name: Option<String>) -> FileTransfer {
+        let id = transfer_id();
+        let channel = peer.create_data_channel(&format!("{}{}", LABEL_PREFIX, id));
+        channel.set_buffered_amount_low_threshold(LOW_BUFFERED);
+        let state = Rc::new(self.state(id.clone(), channel.clone(), "send"));
+        *state.name.borrow_mut() = name
+            .or_else(|| get![file => "name"].as_string())
+            .unwrap_or_else(|| "file".to_string());
+        state.size.set(file.size());
+        let message_state = state.clone();
+        let on_message = Closure::wrap(Box::new(move |event: JsValue| {
+            let text = get![event => "data"].as_string().unwrap_or_default();
+            match TransferMessage::parse(&text) {
+                Ok(TransferMessage::Received) => message_state.replied(true),
+                Ok(TransferMessage::Failed) => message_state.replied(false),
+                _ => message_state.channel.close()
+            }
+        }) as Box<dyn FnMut(JsValue)>);
+        let closed_state = state.clone();
+        let on_close = self.close_cb(id.clone(), move || closed_state.closed());
+        channel.set_onmessage(on_message.as_ref().dyn_ref());
+        channel.set_onclose(on_close.as_ref().dyn_ref());
+        self.active.borrow_mut().insert(id.clone(), ActiveTransfer {
+            channel: channel.clone(),
+            _callbacks: vec![on_message, on_close],
+        });
+        let promise = future_to_promise(async move {
+            match state.send(file).await {
+                Ok(()) => Ok(JsValue::from_str(&state.id)),
+                Err(e) => {
+                    // A cancelled transfer was reported when its channel closed
+                    let cancelled = matches!(e, Error::TransferCancelled(_));
+                    let error: JsValue = e.into();
+                    if !cancelled && !state.done.replace(true) {
+                        state.fail(error.clone());
+                    }
+                    cancel_channel(&state.channel);
+                    Err(error)
+                }
+            }
+        });
+        FileTransfer { id, promise, channel }
+    }
+
+    // Returns false for the channels that don't carry a file
+    pub fn receive(self: &Rc<Self>, channel: RtcDataChannel) -> bool {
+        let id = match channel.label().strip_prefix(LABEL_PREFIX) {
+            Some(id) => id.to_string(),
+            None => return false
+        };
+        let file = Rc::new(IncomingFile {
+            state: self.state(id.clone(), channel.clone(), "receive"),
+            mime_type: RefCell::new(String::new()),
+            hasher: RefCell::new(Sha256::default()),
+            parts: js_sys::Array::new(),
+            received: Cell::new(0.),
+        });
+        let message_file = file.clone();
+        let on_message = Closure::wrap(Box::new(move |event: JsValue| {
+            if let Err(e) = message_file.on_message(get![event => "data"]) {
+                console::error_1(&e.into());
+                cancel_channel(&message_file.state.channel);
+            }
+        }) as Box<dyn FnMut(JsValue)>);
+        let on_close = self.close_cb(id.clone(), move || file.state.closed());
+        channel.set_onmessage(on_message.as_ref().dyn_ref());
+        channel.set_onclose(on_close.as_ref().dyn_ref());
+        self.active.borrow_mut().insert(id, ActiveTransfer {
+            channel,
+            _callbacks: vec![on_message, on_close],
+        });
+        true
+    }
+
+    pub fn cancel(&self, id: &str) -> bool {
+        match self.active.borrow().get(id) {
+            Some(transfer) => {
+                cancel_channel(&transfer.channel);
+                true
+            }
+            None => false
+        }
+    }
+
+    pub fn cancel_all(&self) {
+        for transfer in self.active.borrow().values() {
+            cancel_channel(&transfer.channel);
+        }
+    }
+}
+
+#[wasm_bindgen]
+pub struct FileTransfer {
+    id: String,
+    promise: js_sys::Promise,
+    channel: RtcDataChannel,
+}
+
+#[wasm_bindgen]
+impl FileTransfer {
+    pub fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    // Resolves with the transfer id once the receiver has checked the file against its hash,
+    // rejects when the check fails, on errors and when the transfer is cancelled or closed
+    pub fn get_promise(&self) -> js_sys::Promise {
+        self.promise.clone()
+    }
+
+    pub fn cancel(&self) {
+        cancel_channel(&self.channel);
+    }
+}
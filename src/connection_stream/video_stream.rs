@@ -1,26 +1,181 @@
 use wasm_bindgen::prelude::*;
 use web_sys::*;
 use wasm_bindgen::JsCast;
-use wasm_bindgen_futures::future_to_promise;
-use crate::js_extend::ConnectionOffer;
-use crate::js_await;
+use wasm_bindgen_futures::{future_to_promise, spawn_local};
+use crate::js_extend::{ConnectionOffer, Interval, set_timeout};
+use crate::{js_await, set};
+use std::cell::Cell;
 use std::rc::Rc;
 use wasm_bindgen::__rt::std::collections::HashMap;
 use wasm_bindgen::__rt::WasmRefCell;
 
 use crate::connection_stream::connection::Connection;
-use crate::connection_stream::render_video::{create_video, VideoRenderer};
+use crate::connection_stream::render_video::{create_video, TileSizeCb, VideoRenderer};
+use crate::connection_stream::ice::{CandidateFilter, IceConfig};
+use crate::connection_stream::options::StreamingOptions;
+use crate::connection_stream::events::Events;
+use crate::connection_stream::capture::{apply_media_state, capture_result, CaptureOptions, DeviceKind, ScreenShareOptions};
+use crate::connection_stream::screen::ScreenShare;
+use crate::connection_stream::transfer::FileTransfer;
+use crate::connection_stream::overlay::RendererOptions;
+use crate::connection_stream::layout::{self, Rect};
+use crate::connection_stream::bandwidth::{auto_bitrate_kbps, SendLimits};
+use crate::connection_stream::control::{ControlMessage, MediaState};
+use crate::error::Error;
 use wasm_bindgen::__rt::core::cell::RefCell;
 
-#[derive(Serialize)]
-pub struct VideoConstraints {
-    width: i32,
-    height: i32,
-    #[serde(rename(serialize = "frameRate"))]
-    frame_rate: i32,
+type ConnectionDict = Rc<WasmRefCell<HashMap<String, Connection>>>;
+type ScreenCell = Rc<RefCell<Option<ScreenShare>>>;
+
+const SELF_ID: &str = "self";
+const CAMERA_STREAM: &str = "camera";
+const SCREEN_STREAM: &str = "screen";
+
+const ICE_REFRESH_RETRY_MS: i32 = 10_000;
+
+fn apply_ice_config(ice: &IceConfig, connections: &ConnectionDict) -> Result<(), JsValue> {
+    let config = ice.create_config()?;
+    for connection in connections.borrow().values() {
+        connection.set_configuration(&config)?;
+    }
+    Ok(())
 }
 
-type ConnectionDict = Rc<WasmRefCell<HashMap<String, Connection>>>;
+async fn refresh_ice_servers(ice: Rc<IceConfig>, connections: ConnectionDict) -> Result<JsValue, Error> {
+    ice.fetch().await?;
+    apply_ice_config(&ice, &connections)?;
+    if let Some(delay) = ice.refresh_delay_ms() {
+        schedule_ice_refresh(ice, connections, delay)?;
+    }
+    Ok(JsValue::TRUE)
+}
+
+fn schedule_ice_refresh(ice: Rc<IceConfig>, connections: ConnectionDict, delay: i32) -> Result<i32, JsValue> {
+    let generation = ice.next_generation();
+    set_timeout(move || {
+        if ice.generation() != generation {
+            return;
+        }
+        spawn_local(async move {
+            if let Err(e) = refresh_ice_servers(ice.clone(), connections.clone()).await {
+                console::error_2(&"Could not refresh ICE servers".into(), &e.into());
+                let _ = schedule_ice_refresh(ice, connections, ICE_REFRESH_RETRY_MS);
+            }
+        });
+    }, delay)
+}
+
+async fn enumerate_devices() -> Result<JsValue, Error> {
+    let devices = web_sys::window().unwrap().navigator().media_devices()?;
+    Ok(js_await![devices.enumerate_devices()?]?)
+}
+
+async fn switch_device(kind: DeviceKind, options: CaptureOptions, stream: MediaStream,
+                       connections: ConnectionDict, media: MediaState) -> Result<JsValue, Error> {
+    let devices = web_sys::window().unwrap().navigator().media_devices()?;
+    let promise = devices.get_user_media_with_constraints(&options.to_constraints()?).map_err(Error::from_media)?;
+    let new_stream: MediaStream = js_await![promise].map_err(Error::from_media)?.dyn_into()?;
+    let track: MediaStreamTrack = new_stream.get_tracks().get(0).dyn_into()?;
+    apply_media_state(&new_stream, media);
+    let old_tracks: Vec<MediaStreamTrack> = stream.get_tracks().iter()
+        .map(|track| track.unchecked_into::<MediaStreamTrack>())
+        .filter(|track| track.kind() == kind.track_kind())
+        .collect();
+    stream.add_track(&track);
+    // Nothing to replace when the stream was captured without this kind of device
+    if old_tracks.is_empty() {
+        for connection in connections.borrow().values() {
+            if connection.add_track(&track, &stream) {
+                connection.renegotiate();
+            }
+        }
+    }
+    let mut replaced = vec![];
+    for old_track in old_tracks {
+        stream.remove_track(&old_track);
+        old_track.stop();
+        for connection in connections.borrow().values() {
+            if let Some(promise) = connection.replace_track(&old_track.id(), &track) {
+                replaced.push(promise);
+            }
+        }
+    }
+    for promise in replaced {
+        js_await![promise]?;
+    }
+    Ok(capture_result(&stream))
+}
+
+fn emit_screen_share(events: &Events, active: bool) {
+    let detail = js_sys::Object::new();
+    set![detail => "active", active];
+    events.emit("screenshare", SELF_ID, &detail);
+}
+
+// Sends the media state to the peers when it changes. The camera is reported on
+// while a screen replaces it, the peers would draw the camera-off placeholder over
+// the screen otherwise.
+fn announce_media_state(announced: &Cell<MediaState>, media: MediaState, screen: &ScreenCell,
+                        connections: &ConnectionDict) {
+    let replacing = screen.borrow().as_ref().map(ScreenShare::replaces_camera).unwrap_or(false);
+    let media = MediaState { video: media.video || replacing, ..media };
+    if announced.replace(media) == media {
+        return;
+    }
+    let message = ControlMessage::Media(media);
+    for connection in connections.borrow().values() {
+        if let Err(e) = connection.send_control(&message) {
+            console::error_1(&e.into());
+        }
+    }
+}
+
+fn stop_screen_share(screen: &ScreenCell, connections: &ConnectionDict, renderer: &RefCell<VideoRenderer>,
+                     events: &Events, media: &Cell<MediaState>, announced: &Cell<MediaState>) -> bool {
+    let share = match screen.borrow_mut().take() {
+        Some(share) => share,
+        None => return false
+    };
+    for connection in connections.borrow().values() {
+        share.unpublish(connection);
+    }
+    share.stop();
+    renderer.borrow_mut().remove_video(&(SELF_ID.to_string(), SCREEN_STREAM.to_string()));
+    announce_media_state(announced, media.get(), screen, connections);
+    emit_screen_share(events, false);
+    true
+}
+
+// The content box, what ResizeObserver reports: the client size without the padding
+fn content_size(element: &HtmlElement) -> (f64, f64) {
+    let (width, height) = (element.client_width() as f64, element.client_height() as f64);
+    let style = match web_sys::window().and_then(|window| window.get_computed_style(element).ok().flatten()) {
+        Some(style) => style,
+        None => return (width, height)
+    };
+    let px = |property: &str| style.get_property_value(property).ok()
+        .and_then(|value| value.trim_end_matches("px").parse::<f64>().ok())
+        .unwrap_or(0.);
+    ((width - px("padding-left") - px("padding-right")).max(0.), (height - px("padding-top") - px("padding-bottom")).max(0.))
+}
+
+// Matches only at the current devicePixelRatio, so it changes as soon as the ratio does
+fn pixel_ratio_query() -> Option<MediaQueryList> {
+    let window = web_sys::window()?;
+    window.match_media(&format!("(resolution: {}dppx)", window.device_pixel_ratio())).ok().flatten()
+}
+
+// Spreads the upload budget over the peers, or lifts the automatic cap
+fn update_auto_bitrate(connections: &ConnectionDict, enabled: bool) {
+    let connections = connections.borrow();
+    let kbps = match enabled {
+        true => auto_bitrate_kbps(connections.len()),
+        false => None
+    };
+    for connection in connections.values() {
+        connection.set_auto_bitrate(kbps);
+    }
+}
 
 #[wasm_bindgen]
 pub struct Streaming {
@@ -29,127 +184,423 @@ pub struct Streaming {
     canvas: Rc<web_sys::HtmlCanvasElement>,
     connections: ConnectionDict,
     renderer: Rc<RefCell<VideoRenderer>>,
+    ice: Rc<IceConfig>,
+    events: Rc<Events>,
+    options: Rc<StreamingOptions>,
+    capture: CaptureOptions,
+    media: Rc<Cell<MediaState>>,
+    // What the peers were told, see announce_media_state
+    announced_media: Rc<Cell<MediaState>>,
+    screen: ScreenCell,
+    stats_sampler: Option<Interval>,
+    // Applied to the connections created from now on
+    limits: SendLimits,
+    auto_bitrate: Rc<Cell<bool>>,
+    // Resizes the canvas with the container
+    resize_observer: ResizeObserver,
+    _on_resize: Closure<dyn FnMut(JsValue)>,
+    // Rescales the canvas when devicePixelRatio changes, replaced on every change
+    pixel_ratio_query: Rc<RefCell<Option<MediaQueryList>>>,
+    _on_pixel_ratio: Closure<dyn FnMut(JsValue)>,
+    _on_device_change: Closure<dyn FnMut(JsValue)>,
+}
+
+impl Drop for Streaming {
+    fn drop(&mut self) {
+        self.resize_observer.disconnect();
+        if let Some(query) = self.pixel_ratio_query.borrow().as_ref() {
+            query.set_onchange(None);
+        }
+        if let Ok(devices) = web_sys::window().unwrap().navigator().media_devices() {
+            devices.set_ondevicechange(None);
+        }
+    }
 }
 
 
 #[wasm_bindgen]
 impl Streaming {
     #[wasm_bindgen(constructor)]
-    pub fn new(dom_element: web_sys::HtmlElement) -> Result<Streaming, JsValue> {
+    pub fn new(dom_element: web_sys::HtmlElement, options: JsValue) -> Result<Streaming, Error> {
+        let options = StreamingOptions::from_js(options)?;
         let video = create_video(true)?;
         let document = web_sys::window().unwrap().document().unwrap();
         let canvas = document.create_element("canvas")?.unchecked_into::<HtmlCanvasElement>();
-        let width = dom_element.offset_width();
-        let height = dom_element.offset_height();
-        canvas.set_width(width as u32);
-        canvas.set_height(height as u32);
+        let (width, height) = content_size(&dom_element);
+        // Inline, the line gap under the canvas would grow the container on every resize
+        canvas.style().set_property("display", "block")?;
         let canvas_rc = Rc::new(canvas);
-        let renderer = VideoRenderer::new(canvas_rc.clone(), width, height)?;
+        let mut renderer = VideoRenderer::new(canvas_rc.clone(), width, height)?;
+        let connections = Rc::new(WasmRefCell::new(HashMap::new()));
+        renderer.set_on_tile_size(Streaming::tile_size_cb(connections.clone()));
+        let renderer = Rc::new(RefCell::new(renderer));
+        let _on_resize = Streaming::resize_cb(renderer.clone(), dom_element.clone());
+        let resize_observer = ResizeObserver::new(_on_resize.as_ref().unchecked_ref())?;
+        resize_observer.observe(&dom_element);
+        let pixel_ratio_query = Rc::new(RefCell::new(pixel_ratio_query()));
+        let _on_pixel_ratio = Streaming::pixel_ratio_cb(renderer.clone(), pixel_ratio_query.clone());
+        if let Some(query) = pixel_ratio_query.borrow().as_ref() {
+            query.set_onchange(_on_pixel_ratio.as_ref().dyn_ref());
+        }
+        let events = Rc::new(Events::default());
+        let _on_device_change = Streaming::device_change_cb(events.clone());
+        if let Ok(devices) = web_sys::window().unwrap().navigator().media_devices() {
+            devices.set_ondevicechange(_on_device_change.as_ref().dyn_ref());
+        }
         Ok(Streaming {
             dom_element,
             self_video: video,
             canvas: canvas_rc,
-            connections: Rc::new(WasmRefCell::new(HashMap::new())),
-            renderer: Rc::new(RefCell::new(renderer)),
+            connections,
+            renderer,
+            ice: Rc::new(IceConfig::new(options.ice_servers.clone(), options.candidate_filter.into())),
+            events,
+            options: Rc::new(options),
+            capture: CaptureOptions::default(),
+            media: Rc::new(Cell::new(MediaState::default())),
+            announced_media: Rc::new(Cell::new(MediaState::default())),
+            screen: Rc::new(RefCell::new(None)),
+            stats_sampler: None,
+            limits: SendLimits::default(),
+            auto_bitrate: Rc::new(Cell::new(false)),
+            resize_observer,
+            _on_resize,
+            pixel_ratio_query,
+            _on_pixel_ratio,
+            _on_device_change,
         })
     }
 
-    pub fn set_on_ice_candidate(&mut self, id: String, closure: js_sys::Function) {
-        match self.connections.borrow_mut().get_mut(&id) {
-            Some(connection) => { connection.set_on_ice_candidate(closure); }
-            None => panic!("Id {} does not exist", &id)
-        }
+    pub fn set_on_ice_candidate(&mut self, id: String, closure: js_sys::Function) -> Result<(), Error> {
+        self.with_connection(&id, |connection| {
+            connection.set_on_ice_candidate(closure);
+            Ok(())
+        })
     }
 
-    pub fn add_ice_candidate(&mut self, id: String, candidate: RtcIceCandidate) -> js_sys::Promise {
-        match self.connections.borrow_mut().get_mut(&id) {
-            Some(connection) => { connection.add_ice_candidate(candidate) }
-            None => panic!("Id {} does not exist", &id)
-        }
+    pub fn set_on_description(&mut self, id: String, closure: js_sys::Function) -> Result<(), Error> {
+        self.with_connection(&id, |connection| {
+            connection.set_on_description(closure);
+            Ok(())
+        })
     }
 
-    pub fn create_offer(&mut self, id: String) -> Result<ConnectionOffer, JsValue> {
-        //let stream = match get_canvas_stream(self.self_canvas.clone(), 20.0) {
-        let stream = match self.self_video.as_ref().src_object() {
-            Some(s) => s,
-            None => panic!("Stream not set")
+    pub fn connect(&mut self, id: String) -> Result<(), Error> {
+        let stream = self.local_stream()?;
+        self.with_connection(&id, |connection| {
+            if !connection.is_perfect() {
+                return Err(Error::NegotiationMode("Perfect negotiation is not enabled, use create_offer"));
+            }
+            connection.add_stream(&stream, false);
+            self.publish_screen(connection);
+            Ok(())
+        })
+    }
+
+    pub fn handle_description(&mut self, id: String, description: RtcSessionDescriptionInit) -> Result<js_sys::Promise, Error> {
+        self.with_connection(&id, |connection| connection.handle_description(description))
+    }
+
+    pub fn add_track(&mut self, track: MediaStreamTrack, stream: Option<MediaStream>) -> Result<(), Error> {
+        let stream = match stream {
+            Some(stream) => stream,
+            None => {
+                let local = self.local_stream()?;
+                local.add_track(&track);
+                local
+            }
         };
-        match self.connections.borrow().get(&id) {
-            Some(connection) => { Ok(connection.create_offer(&stream)) }
-            None => Err(format!("Id {} does not exist", &id).into())
+        for connection in self.connections.borrow().values() {
+            if connection.add_track(&track, &stream) {
+                connection.renegotiate();
+            }
         }
+        Ok(())
     }
 
-    pub fn accept_offer(&mut self, id: String, offer: RtcSessionDescriptionInit) -> Result<ConnectionOffer, JsValue> {
-        let stream = match self.self_video.as_ref().src_object() {
-            Some(s) => s,
-            None => panic!("Stream not set")
-        };
-        match self.connections.borrow_mut().get_mut(&id) {
-            Some(connection) => { Ok(connection.accept_offer(offer, &stream)) }
-            None => Err(format!("Id {} does not exist", &id).into())
+    pub fn remove_track(&mut self, track: MediaStreamTrack) {
+        if let Some(local) = self.self_video.as_ref().src_object() {
+            local.remove_track(&track);
+        }
+        for connection in self.connections.borrow().values() {
+            if connection.remove_track(&track.id()) {
+                connection.renegotiate();
+            }
         }
     }
 
+    pub fn add_ice_candidate(&mut self, id: String, candidate: RtcIceCandidate) -> Result<js_sys::Promise, Error> {
+        self.with_connection(&id, |connection| Ok(connection.add_ice_candidate(candidate)))
+    }
 
-    pub fn accept_answer(&mut self, id: String, answer: RtcSessionDescriptionInit) -> Result<ConnectionOffer, JsValue> {
-        match self.connections.borrow_mut().get_mut(&id) {
-            Some(connection) => { Ok(connection.accept_answer(answer)) }
-            None => Err(format!("Id {} does not exist", &id).into())
-        }
+    pub fn create_offer(&mut self, id: String) -> Result<ConnectionOffer, Error> {
+        //let stream = match get_canvas_stream(self.self_canvas.clone(), 20.0) {
+        let stream = self.local_stream()?;
+        self.with_classic_connection(&id, |connection| {
+            let offer = connection.create_offer(&stream);
+            self.publish_screen(connection);
+            Ok(offer)
+        })
+    }
+
+    pub fn accept_offer(&mut self, id: String, offer: RtcSessionDescriptionInit) -> Result<ConnectionOffer, Error> {
+        let stream = self.local_stream()?;
+        let screen = self.screen.clone();
+        let connections = self.connections.clone();
+        let peer_id = id.clone();
+        // The screen gets no section in the peer's offer, it is offered after answering
+        let on_answered = Box::new(move || {
+            if let (Some(share), Some(connection)) = (screen.borrow().as_ref(), connections.borrow().get(&peer_id)) {
+                share.publish(connection);
+            }
+        });
+        self.with_classic_connection(&id, |connection| Ok(connection.accept_offer(offer, &stream, on_answered)))
+    }
+
+
+    pub fn accept_answer(&mut self, id: String, answer: RtcSessionDescriptionInit) -> Result<ConnectionOffer, Error> {
+        self.with_classic_connection(&id, |connection| Ok(connection.accept_answer(answer)))
     }
 
-    pub fn load_video(&mut self) -> Result<js_sys::Promise, JsValue> {
+    pub fn load_video(&mut self, options: JsValue) -> Result<js_sys::Promise, Error> {
         let devices = web_sys::window().unwrap().navigator().media_devices()?;
-        let mut constraints = MediaStreamConstraints::new();
-        constraints.audio(&JsValue::TRUE);
-        let _video_constraints = VideoConstraints { width: 300, height: 300, frame_rate: 10 };
-        constraints.video(&JsValue::TRUE);
-        let promise = devices.get_user_media_with_constraints(&constraints)?;
+        self.capture = CaptureOptions::from_js(options)?;
+        let constraints = self.capture.to_constraints()?;
+        let promise = devices.get_user_media_with_constraints(&constraints).map_err(Error::from_media)?;
         let video = self.self_video.clone();
         let canvas = self.canvas.clone();
+        let media = self.media.clone();
 
-        let mut renderer = self.renderer.borrow_mut();
-        self.dom_element.append_child(&canvas)?;
-        renderer.add_video("self".to_string(), video.clone());
-        renderer.start()?;
+        if canvas.parent_node().is_none() {
+            let mut renderer = self.renderer.borrow_mut();
+            self.dom_element.append_child(&canvas)?;
+            renderer.add_video((SELF_ID.to_string(), CAMERA_STREAM.to_string()), video.clone(), true);
+            renderer.set_media_state(SELF_ID, media.get());
+            renderer.start()?;
+        }
 
         Ok(future_to_promise(async move {
-            let js_stream: JsValue = match js_await![promise] {
-                Ok(val) => val,
-                Err(e) => panic!("{:?}", e)
-            };
-            match js_stream.dyn_into::<MediaStream>() {
-                Ok(stream) => {
-                    video.set_src_object(Some(&stream));
-                    let _p = video.play().unwrap();
-                    Ok(stream.unchecked_into())
-                }
-                Err(e) => Err(e)
+            let js_stream: JsValue = js_await![promise].map_err(Error::from_media)?;
+            let stream: MediaStream = js_stream.dyn_into()?;
+            apply_media_state(&stream, media.get());
+            video.set_src_object(Some(&stream));
+            let _ = video.play();
+            Ok(capture_result(&stream))
+        }))
+    }
+
+    pub fn enumerate_devices(&self) -> js_sys::Promise {
+        future_to_promise(async move {
+            Ok(enumerate_devices().await?)
+        })
+    }
+
+    pub fn switch_camera(&mut self, device_id: String) -> Result<js_sys::Promise, Error> {
+        self.switch_device(DeviceKind::Camera, device_id)
+    }
+
+    pub fn switch_microphone(&mut self, device_id: String) -> Result<js_sys::Promise, Error> {
+        self.switch_device(DeviceKind::Microphone, device_id)
+    }
+
+    pub fn set_audio_enabled(&mut self, enabled: bool) {
+        let media = MediaState { audio: enabled, ..self.media.get() };
+        self.set_media_state(media);
+    }
+
+    pub fn set_video_enabled(&mut self, enabled: bool) {
+        let media = MediaState { video: enabled, ..self.media.get() };
+        self.set_media_state(media);
+    }
+
+    pub fn start_screen_share(&mut self, options: JsValue) -> Result<js_sys::Promise, Error> {
+        if self.screen.borrow().is_some() {
+            return Err(Error::ScreenShareActive);
+        }
+        let options = ScreenShareOptions::from_js(options)?;
+        let devices = web_sys::window().unwrap().navigator().media_devices()?;
+        let promise = devices.get_display_media_with_constraints(&options.to_constraints()?).map_err(Error::from_media)?;
+        let camera = match options.replace_camera {
+            true => Some(self.local_stream()?),
+            false => None
+        };
+        let on_ended = self.screen_ended_cb();
+        let screen = self.screen.clone();
+        let connections = self.connections.clone();
+        let renderer = self.renderer.clone();
+        let events = self.events.clone();
+        let media = self.media.clone();
+        let announced = self.announced_media.clone();
+        Ok(future_to_promise(async move {
+            let stream: MediaStream = js_await![promise].map_err(Error::from_media)?.dyn_into()?;
+            let share = ScreenShare::new(stream.clone(), camera, on_ended)?;
+            // Another share may have started while the user was picking a screen
+            if screen.borrow().is_some() {
+                share.stop();
+                return Err(Error::ScreenShareActive.into());
+            }
+            renderer.borrow_mut().add_video((SELF_ID.to_string(), SCREEN_STREAM.to_string()), share.video(), false);
+            for connection in connections.borrow().values() {
+                share.publish(connection);
             }
+            *screen.borrow_mut() = Some(share);
+            announce_media_state(&announced, media.get(), &screen, &connections);
+            emit_screen_share(&events, true);
+            Ok(stream.into())
         }))
     }
 
+    pub fn stop_screen_share(&mut self) -> bool {
+        stop_screen_share(&self.screen, &self.connections, &self.renderer, &self.events,
+                          &self.media, &self.announced_media)
+    }
+
     fn on_state(&mut self, id: String) -> Box<dyn Fn()> {
         let rc = self.connections.clone();
         let renderer = self.renderer.clone();
+        let events = self.events.clone();
+        let auto_bitrate = self.auto_bitrate.clone();
         Box::new(move || {
             let connections = &*rc;
-            if let Some(_connection) = connections.borrow_mut().remove(&id) {
-                renderer.borrow_mut().remove_video(&id);
+            let removed = connections.borrow_mut().remove(&id);
+            if let Some(connection) = removed {
+                connection.close();
+                renderer.borrow_mut().remove_peer(&id);
+                update_auto_bitrate(&rc, auto_bitrate.get());
+                events.emit("peerremoved", &id, &JsValue::UNDEFINED);
             }
         })
     }
 
-    pub fn create_connection(&mut self, id: String) -> Result<JsValue, JsValue> {
+    pub fn create_connection(&mut self, id: String) -> Result<JsValue, Error> {
         if !self.connections.borrow().contains_key(&id) {
-            let co = Connection::new(id.clone(), &mut *self.renderer.clone().borrow_mut(), self.on_state(id.clone()));
+            // The peer starts with the expired servers, its first description waits for the new ones
+            if self.ice.has_provider() && self.ice.is_expired() {
+                let _ = self.refresh_ice_servers();
+            }
+            let on_state = self.on_state(id.clone());
+            let co = Connection::new(
+                id.clone(),
+                self.renderer.clone(),
+                on_state,
+                self.ice.clone(),
+                self.events.clone(),
+                self.options.clone(),
+                self.announced_media.clone(),
+            )?;
+            co.set_limits(self.limits);
             self.connections.borrow_mut().insert(id, co);
+            update_auto_bitrate(&self.connections, self.auto_bitrate.get());
             return Ok(JsValue::TRUE);
         }
-        Err(JsValue::from_str("Id already created"))
+        Err(Error::PeerExists(id))
+    }
+
+
+    pub fn set_ice_servers_provider(&mut self, provider: js_sys::Function) -> js_sys::Promise {
+        self.ice.set_provider(provider);
+        self.refresh_ice_servers()
+    }
+
+    pub fn set_candidate_filter(&mut self, filter: JsValue) -> Result<(), Error> {
+        self.ice.set_filter(CandidateFilter::from_js(filter)?);
+        Ok(apply_ice_config(&self.ice, &self.connections)?)
+    }
+
+    // New descriptions wait for the refresh, see IceConfig::ready
+    pub fn refresh_ice_servers(&self) -> js_sys::Promise {
+        let ice = self.ice.clone();
+        let connections = self.connections.clone();
+        let promise = future_to_promise(async move {
+            Ok(refresh_ice_servers(ice, connections).await?)
+        });
+        self.ice.set_refreshing(promise.clone());
+        promise
+    }
+
+    pub fn send(&mut self, id: String, data: JsValue) -> Result<(), Error> {
+        self.with_connection(&id, |connection| connection.send(&data))
+    }
+
+    // Sends to every peer whose channel is open, returns how many were reached
+    pub fn broadcast(&mut self, data: JsValue) -> Result<u32, Error> {
+        let mut sent = 0;
+        for connection in self.connections.borrow().values() {
+            match connection.send(&data) {
+                Ok(()) => sent += 1,
+                Err(Error::ChannelClosed(_)) => {}
+                Err(e) => return Err(e)
+            }
+        }
+        Ok(sent)
+    }
+
+    // Progress is reported through the filestart, fileprogress, filesent,
+    // filereceived, filefailed and filecancelled events
+    pub fn send_file(&mut self, id: String, file: Blob, name: Option<String>) -> Result<FileTransfer, Error> {
+        self.with_connection(&id, |connection| Ok(connection.send_file(file, name)))
+    }
+
+    pub fn cancel_transfer(&mut self, transfer_id: String) -> bool {
+        self.connections.borrow().values().any(|connection| connection.cancel_transfer(&transfer_id))
+    }
+
+    // Shorthand for add_event_listener("message", listener), called as listener(id, data)
+    pub fn on_message(&mut self, listener: js_sys::Function) {
+        self.events.add("message".to_string(), listener);
+    }
+
+    // Without an id, applies to every peer, current and future
+    pub fn set_max_bitrate(&mut self, id: Option<String>, kbps: Option<u32>) -> Result<(), Error> {
+        self.update_limits(id, |limits| limits.max_bitrate_kbps = kbps)
+    }
+
+    pub fn set_max_framerate(&mut self, id: Option<String>, fps: Option<f64>) -> Result<(), Error> {
+        self.update_limits(id, |limits| limits.max_framerate = fps)
+    }
+
+    pub fn set_scale_resolution_down_by(&mut self, id: Option<String>, factor: Option<f64>) -> Result<(), Error> {
+        self.update_limits(id, |limits| limits.scale_resolution_down_by = factor)
+    }
+
+    // Lowers the bitrate cap as peers join, see auto_bitrate_kbps
+    pub fn set_auto_bitrate(&mut self, enabled: bool) {
+        self.auto_bitrate.set(enabled);
+        update_auto_bitrate(&self.connections, enabled);
+    }
+
+    // Asks `id` to only send these simulcast layers, e.g. ["q"] for a thumbnail.
+    // The managed grid asks for a layer on its own as its tiles resize
+    pub fn set_simulcast_layers(&mut self, id: String, rids: Vec<String>) -> Result<(), Error> {
+        self.with_connection(&id, |connection| connection.request_layers(rids))
+    }
+
+    pub fn get_stats(&mut self, id: String) -> Result<js_sys::Promise, Error> {
+        self.with_connection(&id, |connection| Ok(connection.get_stats()))
+    }
+
+    // Samples every peer each `interval_ms`, see the "stats" and "qualitychange" events
+    pub fn start_stats_sampler(&mut self, interval_ms: i32) -> Result<(), Error> {
+        let connections = self.connections.clone();
+        self.stats_sampler = Some(Interval::new(move || {
+            for connection in connections.borrow().values() {
+                connection.sample_stats();
+            }
+        }, interval_ms)?);
+        Ok(())
     }
 
+    pub fn stop_stats_sampler(&mut self) {
+        self.stats_sampler = None;
+    }
+
+    pub fn add_event_listener(&mut self, name: String, listener: js_sys::Function) {
+        self.events.add(name, listener);
+    }
+
+    pub fn remove_event_listener(&mut self, name: String, listener: js_sys::Function) {
+        self.events.remove(&name, &listener);
+    }
 
     pub fn get_ids(&mut self) -> js_sys::Set {
         let set: js_sys::Set = js_sys::Set::new(&JsValue::UNDEFINED);
@@ -163,11 +614,173 @@ impl Streaming {
         self.renderer.borrow_mut().not_managed();
     }
 
-    pub fn set_video_pos(&mut self, id: String, x: f64, y: f64) -> Result<JsValue, JsValue> {
-        self.renderer.borrow_mut().set_video_pos(id, x, y)
+    // One of "grid", "speaker", "sidebar" or "pip"
+    pub fn set_layout(&mut self, name: String) -> Result<(), Error> {
+        self.renderer.borrow_mut().set_layout(layout::from_name(&name)?);
+        Ok(())
+    }
+
+    // The tiles of `id` get the main spot of the speaker, sidebar and pip layouts
+    pub fn set_active_speaker(&mut self, id: Option<String>) {
+        self.renderer.borrow_mut().set_focus(id);
+    }
+
+    pub fn set_video_pos(&mut self, id: String, x: f64, y: f64, stream_id: Option<String>) -> Result<JsValue, Error> {
+        self.renderer.borrow_mut().set_video_pos(id, stream_id, x, y)
+    }
+
+    // Moves and resizes a single tile, only in the unmanaged mode
+    pub fn set_video_rect(&mut self, id: String, x: f64, y: f64, width: f64, height: f64,
+                          stream_id: Option<String>) -> Result<JsValue, Error> {
+        self.renderer.borrow_mut().set_video_rect(id, stream_id, Rect::new(x, y, width, height))
+    }
+
+    // Shown on the tiles of `id` and used for its initials while its camera is off
+    pub fn set_peer_name(&mut self, id: String, name: Option<String>) {
+        self.renderer.borrow_mut().set_name(&id, name);
+    }
+
+    pub fn set_renderer_options(&mut self, options: JsValue) -> Result<(), Error> {
+        self.renderer.borrow_mut().set_options(RendererOptions::from_js(options)?);
+        Ok(())
     }
 
     pub fn set_dims(&mut self, x: f64, y: f64) {
         self.renderer.borrow_mut().set_dims(x, y);
     }
-}
\ No newline at end of file
+}
+
+impl Streaming {
+    fn device_change_cb(events: Rc<Events>) -> Closure<dyn FnMut(JsValue)> {
+        Closure::wrap(Box::new(move |_event: JsValue| {
+            let events = events.clone();
+            spawn_local(async move {
+                match enumerate_devices().await {
+                    Ok(devices) => events.emit("devicechange", SELF_ID, &devices),
+                    Err(e) => console::error_1(&e.into())
+                }
+            });
+        }) as Box<dyn FnMut(JsValue)>)
+    }
+
+    // Only the container is observed, it is measured like in new
+    fn resize_cb(renderer: Rc<RefCell<VideoRenderer>>, dom_element: HtmlElement) -> Closure<dyn FnMut(JsValue)> {
+        Closure::wrap(Box::new(move |_entries: JsValue| {
+            let (width, height) = content_size(&dom_element);
+            renderer.borrow_mut().resize(width, height);
+        }) as Box<dyn FnMut(JsValue)>)
+    }
+
+    // The query only matches the old ratio, the handler moves to one for the new ratio
+    fn pixel_ratio_cb(renderer: Rc<RefCell<VideoRenderer>>,
+                      query: Rc<RefCell<Option<MediaQueryList>>>) -> Closure<dyn FnMut(JsValue)> {
+        Closure::wrap(Box::new(move |_event: JsValue| {
+            renderer.borrow_mut().rescale();
+            let mut query = query.borrow_mut();
+            let handler = query.as_ref().and_then(MediaQueryList::onchange);
+            if let Some(old) = query.as_ref() {
+                old.set_onchange(None);
+            }
+            *query = pixel_ratio_query();
+            if let Some(new) = query.as_ref() {
+                new.set_onchange(handler.as_ref());
+            }
+        }) as Box<dyn FnMut(JsValue)>)
+    }
+
+    fn tile_size_cb(connections: ConnectionDict) -> TileSizeCb {
+        Box::new(move |id: &str, width, height| {
+            if let Some(connection) = connections.borrow().get(id) {
+                if let Err(e) = connection.set_tile_size(width, height) {
+                    console::error_1(&e.into());
+                }
+            }
+        })
+    }
+
+    fn switch_device(&self, kind: DeviceKind, device_id: String) -> Result<js_sys::Promise, Error> {
+        let stream = self.local_stream()?;
+        let options = self.capture.with_device(kind, device_id);
+        let connections = self.connections.clone();
+        let media = self.media.get();
+        Ok(future_to_promise(async move {
+            Ok(switch_device(kind, options, stream, connections, media).await?)
+        }))
+    }
+
+    fn screen_ended_cb(&self) -> Closure<dyn FnMut(JsValue)> {
+        let screen = self.screen.clone();
+        let connections = self.connections.clone();
+        let renderer = self.renderer.clone();
+        let events = self.events.clone();
+        let media = self.media.clone();
+        let announced = self.announced_media.clone();
+        Closure::wrap(Box::new(move |_event: JsValue| {
+            let (screen, connections, renderer, events) =
+                (screen.clone(), connections.clone(), renderer.clone(), events.clone());
+            let (media, announced) = (media.clone(), announced.clone());
+            // Stopping drops this closure, so leave it first
+            spawn_local(async move {
+                stop_screen_share(&screen, &connections, &renderer, &events, &media, &announced);
+            });
+        }) as Box<dyn FnMut(JsValue)>)
+    }
+
+    fn update_limits<F: Fn(&mut SendLimits)>(&mut self, id: Option<String>, update: F) -> Result<(), Error> {
+        let apply = |connection: &Connection| {
+            let mut limits = connection.limits();
+            update(&mut limits);
+            connection.set_limits(limits);
+        };
+        match id {
+            Some(id) => self.with_connection(&id, |connection| {
+                apply(connection);
+                Ok(())
+            }),
+            None => {
+                update(&mut self.limits);
+                self.connections.borrow().values().for_each(apply);
+                Ok(())
+            }
+        }
+    }
+
+    fn publish_screen(&self, connection: &Connection) {
+        if let Some(share) = self.screen.borrow().as_ref() {
+            share.publish(connection);
+        }
+    }
+
+    // Applied to the local tracks, shown on the "self" tile and sent to every peer
+    fn set_media_state(&self, media: MediaState) {
+        self.media.set(media);
+        if let Ok(stream) = self.local_stream() {
+            apply_media_state(&stream, media);
+        }
+        self.renderer.borrow_mut().set_media_state(SELF_ID, media);
+        announce_media_state(&self.announced_media, media, &self.screen, &self.connections);
+    }
+
+    fn local_stream(&self) -> Result<MediaStream, Error> {
+        self.self_video.as_ref().src_object().ok_or(Error::NoLocalStream)
+    }
+
+    fn with_connection<T, F>(&self, id: &str, f: F) -> Result<T, Error>
+        where F: FnOnce(&Connection) -> Result<T, Error> {
+        match self.connections.borrow().get(id) {
+            Some(connection) => f(connection),
+            None => Err(Error::UnknownPeer(id.to_string()))
+        }
+    }
+
+    // For the create_offer/accept_offer/accept_answer flow, which perfect negotiation replaces
+    fn with_classic_connection<T, F>(&self, id: &str, f: F) -> Result<T, Error>
+        where F: FnOnce(&Connection) -> Result<T, Error> {
+        self.with_connection(id, |connection| {
+            if connection.is_perfect() {
+                return Err(Error::NegotiationMode("Perfect negotiation is enabled, use handle_description"));
+            }
+            f(connection)
+        })
+    }
+}
@@ -0,0 +1,122 @@
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use crate::{get, set};
+
+// Surfaced to JS as an `Error` whose `code` tells the cases apart, the
+// original exception, when there is one, is kept as `cause`.
+pub enum Error {
+    UnknownPeer(String),
+    PeerExists(String),
+    PeerClosed(String),
+    NoLocalStream,
+    ScreenShareActive,
+    ChannelClosed(String),
+    UnsupportedData,
+    TransferCancelled(String),
+    IntegrityCheck(String),
+    NegotiationMode(&'static str),
+    ManagedLayout,
+    SdpRejected(JsValue),
+    PermissionDenied(JsValue),
+    DeviceNotFound(JsValue),
+    DeviceInUse(JsValue),
+    Overconstrained(JsValue),
+    InvalidOptions(String),
+    Js(JsValue),
+}
+
+impl Error {
+    // Classifies a getUserMedia/getDisplayMedia rejection by its DOMException name
+    pub fn from_media(e: JsValue) -> Error {
+        let name = if e.is_object() { get![e => "name"].as_string() } else { None };
+        match name.as_deref() {
+            Some("NotAllowedError") | Some("SecurityError") => Error::PermissionDenied(e),
+            Some("NotFoundError") => Error::DeviceNotFound(e),
+            Some("NotReadableError") | Some("AbortError") => Error::DeviceInUse(e),
+            Some("OverconstrainedError") => Error::Overconstrained(e),
+            _ => Error::Js(e)
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::UnknownPeer(_) => "unknown-peer",
+            Error::PeerExists(_) => "peer-exists",
+            Error::PeerClosed(_) => "peer-closed",
+            Error::NoLocalStream => "no-local-stream",
+            Error::ScreenShareActive => "screen-share-active",
+            Error::ChannelClosed(_) => "channel-closed",
+            Error::UnsupportedData => "unsupported-data",
+            Error::TransferCancelled(_) => "transfer-cancelled",
+            Error::IntegrityCheck(_) => "integrity-check-failed",
+            Error::NegotiationMode(_) => "negotiation-mode",
+            Error::ManagedLayout => "managed-layout",
+            Error::SdpRejected(_) => "sdp-rejected",
+            Error::PermissionDenied(_) => "permission-denied",
+            Error::DeviceNotFound(_) => "device-not-found",
+            Error::DeviceInUse(_) => "device-in-use",
+            Error::Overconstrained(_) => "overconstrained",
+            Error::InvalidOptions(_) => "invalid-options",
+            Error::Js(_) => "js-error",
+        }
+    }
+
+    fn cause(&self) -> Option<&JsValue> {
+        match self {
+            Error::SdpRejected(e) | Error::PermissionDenied(e) | Error::DeviceNotFound(e)
+            | Error::DeviceInUse(e) | Error::Overconstrained(e) | Error::Js(e) => Some(e),
+            _ => None
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            Error::UnknownPeer(id) => format!("Id {} does not exist", id),
+            Error::PeerExists(id) => format!("Id {} already created", id),
+            Error::PeerClosed(id) => format!("The connection to {} was closed", id),
+            Error::NoLocalStream => "Stream not set, call load_video first".to_string(),
+            Error::ScreenShareActive => "The screen is already shared, call stop_screen_share first".to_string(),
+            Error::ChannelClosed(id) => format!("The data channel of {} is not open", id),
+            Error::UnsupportedData => "Data must be a string, an ArrayBuffer or a typed array".to_string(),
+            Error::TransferCancelled(id) => format!("Transfer {} was cancelled", id),
+            Error::IntegrityCheck(id) => format!("The file of transfer {} does not match its hash", id),
+            Error::NegotiationMode(message) => message.to_string(),
+            Error::ManagedLayout => "The layout places the tiles, call not_managed first".to_string(),
+            Error::InvalidOptions(message) => format!("Invalid options: {}", message),
+            _ => {
+                let cause = self.cause().unwrap();
+                let message = match cause.dyn_ref::<js_sys::Error>() {
+                    Some(e) => String::from(e.message()),
+                    None => cause.as_string().unwrap_or_else(|| format!("{:?}", cause))
+                };
+                match self {
+                    Error::SdpRejected(_) => format!("Session description rejected: {}", message),
+                    _ => message
+                }
+            }
+        }
+    }
+}
+
+impl From<JsValue> for Error {
+    fn from(e: JsValue) -> Error {
+        Error::Js(e)
+    }
+}
+
+impl From<serde_wasm_bindgen::Error> for Error {
+    fn from(e: serde_wasm_bindgen::Error) -> Error {
+        Error::InvalidOptions(e.to_string())
+    }
+}
+
+impl From<Error> for JsValue {
+    fn from(e: Error) -> JsValue {
+        let error = js_sys::Error::new(&e.message());
+        set![error => "code", e.code()];
+        if let Some(cause) = e.cause() {
+            set![error => "cause", cause.clone()];
+        }
+        error.into()
+    }
+}
@@ -1,4 +1,5 @@
 use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
 
 #[macro_export]
 macro_rules! get {
@@ -45,6 +46,33 @@ impl ConnectionOffer {
     }
 }
 
+pub fn set_timeout<F: FnOnce() + 'static>(cb: F, millis: i32) -> Result<i32, JsValue> {
+    let closure = Closure::once_into_js(cb);
+    web_sys::window().unwrap()
+        .set_timeout_with_callback_and_timeout_and_arguments_0(closure.unchecked_ref(), millis)
+}
+
+// Cleared when dropped
+pub struct Interval {
+    handle: i32,
+    _closure: Closure<dyn FnMut()>,
+}
+
+impl Interval {
+    pub fn new<F: FnMut() + 'static>(cb: F, millis: i32) -> Result<Interval, JsValue> {
+        let closure = Closure::wrap(Box::new(cb) as Box<dyn FnMut()>);
+        let handle = web_sys::window().unwrap()
+            .set_interval_with_callback_and_timeout_and_arguments_0(closure.as_ref().unchecked_ref(), millis)?;
+        Ok(Interval { handle, _closure: closure })
+    }
+}
+
+impl Drop for Interval {
+    fn drop(&mut self) {
+        web_sys::window().unwrap().clear_interval_with_handle(self.handle);
+    }
+}
+
 #[wasm_bindgen]
 extern "C" {
     #[wasm_bindgen(js_namespace = console)]
@@ -6,6 +6,7 @@ extern crate serde_derive;
 
 
 mod connection_stream;
+mod error;
 
 #[macro_use]
 mod js_extend;